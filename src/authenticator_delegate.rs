@@ -8,6 +8,8 @@ use authenticator::Retry;
 use types::RequestError;
 
 use chrono::{DateTime, Local, UTC};
+#[cfg(feature = "qrcode")]
+use qrcode;
 use std::time::Duration;
 
 /// Contains state of pending authentication requests
@@ -17,6 +19,9 @@ pub struct PollInformation {
     pub user_code: String,
     /// ... at the verification URL
     pub verification_url: String,
+    /// The verification URL with the `user_code` already filled in, if the server provides one.
+    /// Visiting it spares the user from typing the code.
+    pub verification_url_complete: Option<String>,
 
     /// The `user_code` expires at the given time
     /// It's the time the user has left to authenticate your application
@@ -26,6 +31,25 @@ pub struct PollInformation {
     pub interval: Duration,
 }
 
+impl PollInformation {
+    /// Returns the URL to encode as a QR code for the user to scan: the one with the user code
+    /// filled in if the server provided it, or else the plain verification URL.
+    pub fn qr_code_url(&self) -> &str {
+        match self.verification_url_complete {
+            Some(ref url) if !url.is_empty() => url.trim(),
+            _ => self.verification_url.trim(),
+        }
+    }
+
+    /// Renders `qr_code_url()` as a QR code of block characters, to be printed on a terminal.
+    /// Only available with the `qrcode` feature.
+    #[cfg(feature = "qrcode")]
+    pub fn qr_code_string(&self) -> Result<String, qrcode::types::QrError> {
+        let code = try!(qrcode::QrCode::new(self.qr_code_url().as_bytes()));
+        Ok(code.render::<qrcode::render::unicode::Dense1x2>().quiet_zone(true).build())
+    }
+}
+
 impl fmt::Display for PollInformation {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
         writeln!(f, "Proceed with polling until {}", self.expires_at)
@@ -41,6 +65,10 @@ pub enum PollError {
     Expired(DateTime<UTC>),
     /// Indicates that the user declined access. String is server response
     AccessDenied,
+    /// The server answered with an error code we do not know how to handle
+    ServerError(String),
+    /// The flow was cancelled through the flag given to `DeviceFlow::with_cancellation()`
+    Cancelled,
 }
 
 impl fmt::Display for PollError {
@@ -49,6 +77,8 @@ impl fmt::Display for PollError {
             PollError::HttpError(ref err) => err.fmt(f),
             PollError::Expired(ref date) => writeln!(f, "Authentication expired at {}", date),
             PollError::AccessDenied => "Access denied by user".fmt(f),
+            PollError::ServerError(ref code) => write!(f, "Server error: {}", code),
+            PollError::Cancelled => "Device flow was cancelled".fmt(f),
         }
     }
 }
@@ -118,9 +148,20 @@ pub trait AuthenticatorDelegate {
     /// * Will be called exactly once, provided we didn't abort during `request_code` phase.
     /// * Will only be called if the Authenticator's flow_type is `FlowType::Device`.
     fn present_user_code(&mut self, pi: &PollInformation) {
-        println!("Please enter {} at {} and grant access to this application",
-                 pi.user_code,
-                 pi.verification_url);
+        match pi.verification_url_complete {
+            // The code is shown anyway, to be compared with the one the page asks to confirm.
+            Some(ref url) => {
+                println!("Please visit {}, check that it shows the code {} and grant access to \
+                          this application",
+                         url,
+                         pi.user_code)
+            }
+            None => {
+                println!("Please enter {} at {} and grant access to this application",
+                         pi.user_code,
+                         pi.verification_url)
+            }
+        }
         println!("Do not close this application until you either denied or granted access.");
         println!("You have time until {}.",
                  pi.expires_at.with_timezone(&Local));
@@ -151,3 +192,38 @@ pub trait AuthenticatorDelegate {
 /// implementation usable in the first place.
 pub struct DefaultAuthenticatorDelegate;
 impl AuthenticatorDelegate for DefaultAuthenticatorDelegate {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn qr_code_url() {
+        let mut pi = PollInformation {
+            user_code: "WDJB-MJHT".to_string(),
+            verification_url: "https://example.com/device".to_string(),
+            verification_url_complete: Some("https://example.com/device?user_code=WDJB-MJHT"
+                .to_string()),
+            expires_at: UTC::now(),
+            interval: Duration::from_secs(5),
+        };
+        assert_eq!(pi.qr_code_url(), "https://example.com/device?user_code=WDJB-MJHT");
+        pi.verification_url_complete = None;
+        assert_eq!(pi.qr_code_url(), "https://example.com/device");
+    }
+
+    #[cfg(feature = "qrcode")]
+    #[test]
+    fn qr_code_string() {
+        let pi = PollInformation {
+            user_code: "WDJB-MJHT".to_string(),
+            verification_url: "https://example.com/device".to_string(),
+            verification_url_complete: None,
+            expires_at: UTC::now(),
+            interval: Duration::from_secs(5),
+        };
+        let qr = pi.qr_code_string().unwrap();
+        assert!(qr.lines().count() > 10);
+        assert!(qr.contains('█'));
+    }
+}
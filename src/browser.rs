@@ -0,0 +1,69 @@
+//! Opening the authorization URL of the `InstalledFlow` in the browser of the user, where there
+//! is one.
+
+use std::io;
+
+/// Shows the user the URL at which they authorize the application, before the
+/// `AuthenticatorDelegate` is asked to present it.
+///
+/// Failing to open the URL doesn't stop the flow, as the delegate presents it anyway; the error
+/// is only logged.
+///
+/// # Examples
+/// ```
+/// # extern crate hyper;
+/// # extern crate yup_oauth2 as oauth2;
+/// use oauth2::{BrowserOpener, InstalledFlow};
+/// use std::io;
+/// use std::sync::Arc;
+///
+/// struct Notify;
+///
+/// impl BrowserOpener for Notify {
+///     fn open(&self, url: &str) -> io::Result<()> {
+///         eprintln!("Authorize at {}", url);
+///         Ok(())
+///     }
+/// }
+///
+/// # fn main() {
+/// let flow = InstalledFlow::new(hyper::Client::new(), None)
+///     .with_browser_opener(Arc::new(Notify));
+/// # }
+/// ```
+pub trait BrowserOpener: Send + Sync {
+    /// Opens `url`, e.g. in the default browser.
+    fn open(&self, url: &str) -> io::Result<()>;
+}
+
+/// Doesn't open anything, which suits headless machines; used unless another `BrowserOpener` is
+/// set. The user follows the URL as the `AuthenticatorDelegate` presents it.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct NoBrowser;
+
+impl BrowserOpener for NoBrowser {
+    fn open(&self, _: &str) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Opens the URL in the default browser of the desktop, through the
+/// [`open`](https://docs.rs/open) crate.
+///
+/// Only available with the `open` feature.
+#[cfg(feature = "open")]
+#[derive(Clone, Copy, Default, Debug)]
+pub struct DesktopBrowser;
+
+#[cfg(feature = "open")]
+impl BrowserOpener for DesktopBrowser {
+    fn open(&self, url: &str) -> io::Result<()> {
+        let status = try!(::open::that(url));
+        if status.success() {
+            Ok(())
+        } else {
+            Err(io::Error::new(io::ErrorKind::Other,
+                               format!("Opening the browser failed: {}", status)))
+        }
+    }
+}
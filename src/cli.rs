@@ -0,0 +1,92 @@
+//! Helpers for command line tools, which tell the user how to authorize them in the terminal.
+//!
+//! Opening the browser with `open_verification_url()` is only available with the `open` feature.
+
+use std::io::{self, Write};
+
+use authenticator_delegate::PollInformation;
+#[cfg(feature = "open")]
+use browser::{BrowserOpener, DesktopBrowser};
+
+/// Writes instructions for authorizing a device to `out`. The URL and the code are on lines of
+/// their own, so that they are easy to copy; if the server sent a URL with the code filled in,
+/// it is offered as well.
+///
+/// # Examples
+/// ```
+/// # extern crate chrono;
+/// # extern crate yup_oauth2 as oauth2;
+/// # use std::time::Duration;
+/// # fn main() {
+/// let pi = oauth2::PollInformation {
+///     user_code: "WDJB-MJHT".to_string(),
+///     verification_url: "https://www.google.com/device".to_string(),
+///     verification_url_complete: None,
+///     expires_at: chrono::UTC::now(),
+///     interval: Duration::from_secs(5),
+/// };
+/// let mut out = Vec::new();
+/// oauth2::write_device_instructions(&mut out, &pi).unwrap();
+/// assert!(String::from_utf8(out).unwrap().contains("\n    WDJB-MJHT\n"));
+/// # }
+/// ```
+pub fn write_device_instructions<W: Write>(out: &mut W, pi: &PollInformation) -> io::Result<()> {
+    try!(writeln!(out, "To authorize this application, visit\n"));
+    try!(writeln!(out, "    {}\n", pi.verification_url.trim()));
+    try!(writeln!(out, "and enter the code\n"));
+    try!(writeln!(out, "    {}\n", pi.user_code));
+    if let Some(ref url) = pi.verification_url_complete {
+        try!(writeln!(out, "or skip entering the code by visiting\n"));
+        try!(writeln!(out, "    {}\n", url.trim()));
+    }
+    writeln!(out,
+             "The code expires at {}.",
+             pi.expires_at.format("%Y-%m-%d %H:%M:%S UTC"))
+}
+
+/// Like `write_device_instructions()`, printing to standard output.
+pub fn print_device_instructions(pi: &PollInformation) -> io::Result<()> {
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    write_device_instructions(&mut out, pi)
+}
+
+/// Opens the verification URL in the user's browser, the one with the code filled in if there is
+/// one. Show the instructions anyway, as there may be no browser to open.
+#[cfg(feature = "open")]
+pub fn open_verification_url(pi: &PollInformation) -> io::Result<()> {
+    DesktopBrowser.open(pi.qr_code_url())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{TimeZone, UTC};
+    use std::time::Duration;
+
+    #[test]
+    fn device_instructions() {
+        let mut pi = PollInformation {
+            user_code: "WDJB-MJHT".to_string(),
+            verification_url: "https://www.google.com/device".to_string(),
+            verification_url_complete: None,
+            expires_at: UTC.ymd(2017, 7, 14).and_hms(2, 40, 0),
+            interval: Duration::from_secs(5),
+        };
+        let mut out = Vec::new();
+        write_device_instructions(&mut out, &pi).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(),
+                   "To authorize this application, visit\n\n    \
+                    https://www.google.com/device\n\nand enter the code\n\n    \
+                    WDJB-MJHT\n\nThe code expires at 2017-07-14 02:40:00 UTC.\n");
+
+        pi.verification_url_complete = Some("https://www.google.com/device?user_code=WDJB-MJHT"
+            .to_string());
+        let mut out = Vec::new();
+        write_device_instructions(&mut out, &pi).unwrap();
+        assert!(String::from_utf8(out).unwrap().contains("WDJB-MJHT\n\nor skip entering the code \
+                                                         by visiting\n\n    \
+                                                         https://www.google.com/device?\
+                                                         user_code=WDJB-MJHT\n\nThe code"));
+    }
+}
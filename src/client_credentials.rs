@@ -0,0 +1,198 @@
+//! The [client credentials grant](https://tools.ietf.org/html/rfc6749#section-4.4), with which
+//! a client obtains tokens for itself, without any user being involved.
+
+use std::borrow::BorrowMut;
+use std::io::Read;
+
+use hyper;
+use hyper::header::{Accept, Authorization, Basic, ContentType, Headers, UserAgent};
+use itertools::Itertools;
+use serde_json as json;
+use url::form_urlencoded;
+
+use error::Error;
+use clock::SystemClock;
+use refresh::token_from_body;
+use types::{ensure_https, ApplicationSecret, ClientAuthMethod, JsonError, Token,
+            DEFAULT_USER_AGENT};
+
+/// Obtains tokens for machine-to-machine communication, authenticating with nothing but the
+/// client credentials of an `ApplicationSecret`.
+///
+/// The tokens have no refresh token; once they expire, just obtain a new one.
+///
+/// # Examples
+/// ```no_run
+/// # extern crate hyper;
+/// # extern crate yup_oauth2 as oauth2;
+/// # fn main() {
+/// # let secret = oauth2::Provider::microsoft().application_secret("client-id", "secret");
+/// let token = oauth2::ClientCredentialsFlow::new(hyper::Client::new())
+///     .obtain_token(&secret, &["https://graph.microsoft.com/.default"])
+///     .unwrap();
+/// # }
+/// ```
+pub struct ClientCredentialsFlow<C> {
+    client: C,
+    client_auth: ClientAuthMethod,
+    user_agent: String,
+    headers: Headers,
+    insecure_http: bool,
+}
+
+impl<C> ClientCredentialsFlow<C>
+    where C: BorrowMut<hyper::Client>
+{
+    /// Returns a flow sending its requests through `client`.
+    pub fn new(client: C) -> ClientCredentialsFlow<C> {
+        ClientCredentialsFlow {
+            client: client,
+            client_auth: ClientAuthMethod::default(),
+            user_agent: DEFAULT_USER_AGENT.to_string(),
+            headers: Headers::new(),
+            insecure_http: false,
+        }
+    }
+
+    /// Choose how to present the client credentials to the token endpoint. By default,
+    /// they are sent in the request body.
+    pub fn with_client_auth(mut self, method: ClientAuthMethod) -> ClientCredentialsFlow<C> {
+        self.client_auth = method;
+        self
+    }
+
+    /// Send `user_agent` as `User-Agent` header, instead of `DEFAULT_USER_AGENT`.
+    pub fn with_user_agent(mut self, user_agent: String) -> ClientCredentialsFlow<C> {
+        self.user_agent = user_agent;
+        self
+    }
+
+    /// Add `headers` to every token request, like an API key the provider requires. They don't
+    /// replace the headers the flow sets itself; see `with_client_auth()` and `with_user_agent()`
+    /// to change those.
+    pub fn with_headers(mut self, headers: Headers) -> ClientCredentialsFlow<C> {
+        self.headers = headers;
+        self
+    }
+
+    /// Allow sending the client credentials to a token endpoint which doesn't use HTTPS, e.g. a
+    /// local test server. By default, `obtain_token()` fails with `Error::InsecureUri` instead.
+    pub fn with_insecure_http(mut self, allow: bool) -> ClientCredentialsFlow<C> {
+        self.insecure_http = allow;
+        self
+    }
+
+    /// Asks the token endpoint of `client_secret` for a token valid for `scopes`. No scope is
+    /// sent if `scopes` is empty, in which case the server grants its default ones.
+    pub fn obtain_token<'a, I, T>(&mut self,
+                                  client_secret: &ApplicationSecret,
+                                  scopes: I)
+                                  -> Result<Token, Error>
+        where T: AsRef<str> + 'a,
+              I: IntoIterator<Item = &'a T>
+    {
+        if !self.insecure_http {
+            try!(ensure_https(&client_secret.token_uri));
+        }
+        let scope = scopes.into_iter()
+            .map(|s| s.as_ref())
+            .intersperse(" ")
+            .collect::<String>();
+        let mut params = vec![("grant_type", "client_credentials")];
+        if !scope.is_empty() {
+            params.push(("scope", &scope));
+        }
+        let body = try!(request_token(self.client.borrow_mut(),
+                                      client_secret,
+                                      self.client_auth,
+                                      params,
+                                      &self.headers,
+                                      &self.user_agent));
+        token_from_body(&body, &SystemClock)
+    }
+}
+
+// Posts `params` to the token endpoint of `client_secret`, followed by the client credentials
+// as `client_auth` says, and returns the body of a successful response. An error response
+// becomes an `Error::AuthError`, or `Error::BadStatus` if it doesn't say what went wrong.
+pub fn request_token<'a>(client: &mut hyper::Client,
+                         client_secret: &'a ApplicationSecret,
+                         client_auth: ClientAuthMethod,
+                         mut params: Vec<(&'a str, &'a str)>,
+                         headers: &Headers,
+                         user_agent: &str)
+                         -> Result<String, Error> {
+    params.push(("client_id", &client_secret.client_id));
+    if client_auth == ClientAuthMethod::RequestBody {
+        params.push(("client_secret", &client_secret.client_secret));
+    }
+    let req = form_urlencoded::serialize(&params);
+
+    let mut request = client.post(&client_secret.token_uri)
+        .headers(headers.clone())
+        .header(ContentType("application/x-www-form-urlencoded".parse().unwrap()))
+        .header(Accept::json())
+        .header(UserAgent(user_agent.to_string()))
+        .body(&*req);
+    if client_auth == ClientAuthMethod::BasicHeader {
+        request = request.header(Authorization(Basic {
+            username: client_secret.client_id.clone(),
+            password: Some(client_secret.client_secret.clone()),
+        }));
+    }
+    let mut res = try!(request.send());
+    let mut body = String::new();
+    try!(res.read_to_string(&mut body));
+
+    if !res.status.is_success() {
+        return match json::from_str::<JsonError>(&body) {
+            Ok(err) => Err(Error::from(err)),
+            Err(_) => Err(Error::BadStatus(res.status)),
+        };
+    }
+    Ok(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use refresh::tests::RecordingConnector;
+    use types::tests::SECRET;
+    use types::ConsoleApplicationSecret;
+
+    #[test]
+    fn client_credentials_flow() {
+        let secret = json::from_str::<ConsoleApplicationSecret>(SECRET).unwrap().installed.unwrap();
+        let connector = RecordingConnector::new(&["HTTP/1.1 200 OK\r\n\
+                                                   \r\n\
+                                                   {\"access_token\":\"1/fFAGRNJru1FTz70BzhT3Zg\",\
+                                                   \"expires_in\":3600,\"token_type\":\"Bearer\"}",
+                                                  "HTTP/1.1 401 Unauthorized\r\n\
+                                                   \r\n\
+                                                   {\"error\":\"invalid_client\"}"]);
+        let requests = connector.requests.clone();
+        let mut flow = ClientCredentialsFlow::new(hyper::Client::with_connector(connector))
+            .with_client_auth(ClientAuthMethod::BasicHeader);
+
+        let token = flow.obtain_token(&secret, &["https://www.googleapis.com/auth/pubsub"])
+            .unwrap();
+        assert_eq!(token.access_token, "1/fFAGRNJru1FTz70BzhT3Zg");
+        assert_eq!(token.refresh_token, "");
+        assert!(!token.expired());
+
+        let request = requests.lock().unwrap()[0].clone();
+        assert!(request.contains("Authorization: Basic "));
+        let body = request.rsplit("\r\n").next().unwrap();
+        assert!(body.starts_with("grant_type=client_credentials\
+                                  &scope=https%3A%2F%2Fwww.googleapis.com%2Fauth%2Fpubsub\
+                                  &client_id="));
+        assert!(!body.contains("client_secret"));
+
+        let no_scopes: [&str; 0] = [];
+        match flow.obtain_token(&secret, &no_scopes) {
+            Err(Error::AuthError(ref err, _)) => assert_eq!(err, "invalid_client"),
+            r => panic!("Expected an AuthError, got {:?}", r),
+        }
+        assert!(!requests.lock().unwrap()[1].contains("scope="));
+    }
+}
@@ -0,0 +1,88 @@
+//! The source of the current time, to decide whether tokens are expired.
+//!
+//! The flows, the `Authenticator`, the `IdTokenVerifier` and `Token` use the system time unless
+//! given another `Clock`, which allows tests to freeze or advance time.
+
+use chrono::{DateTime, Duration, UTC};
+use std::sync::Mutex;
+
+/// Tells the current time.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<UTC>;
+}
+
+/// The time of the system, which is used by default.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<UTC> {
+        UTC::now()
+    }
+}
+
+/// A clock which stands still, except when moved with `set()` or `advance()`.
+///
+/// # Examples
+/// ```
+/// # extern crate chrono;
+/// # extern crate yup_oauth2 as oauth2;
+/// use oauth2::{Clock, MockClock, Token};
+///
+/// # fn main() {
+/// let clock = MockClock::new(chrono::UTC::now());
+/// let token = Token::new("access", "Bearer")
+///     .with_expiry_timestamp(clock.now().timestamp() + 3600);
+/// assert!(!token.expired_with_clock(chrono::Duration::zero(), &clock));
+/// clock.advance(chrono::Duration::hours(2));
+/// assert!(token.expired_with_clock(chrono::Duration::zero(), &clock));
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct MockClock {
+    now: Mutex<DateTime<UTC>>,
+}
+
+impl MockClock {
+    /// Returns a clock showing `now`.
+    pub fn new(now: DateTime<UTC>) -> MockClock {
+        MockClock { now: Mutex::new(now) }
+    }
+
+    /// Moves the clock to `now`, which may be in the past.
+    pub fn set(&self, now: DateTime<UTC>) {
+        *self.now.lock().unwrap() = now;
+    }
+
+    /// Moves the clock forward by `duration`.
+    pub fn advance(&self, duration: Duration) {
+        let mut now = self.now.lock().unwrap();
+        *now = *now + duration;
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> DateTime<UTC> {
+        *self.now.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn mock_clock() {
+        let start = UTC.ymd(2017, 7, 14).and_hms(2, 40, 0);
+        let clock = MockClock::new(start);
+        assert_eq!(clock.now(), start);
+        clock.advance(Duration::seconds(90));
+        assert_eq!(clock.now(), UTC.ymd(2017, 7, 14).and_hms(2, 41, 30));
+        clock.set(start);
+        assert_eq!(clock.now(), start);
+
+        let system = SystemClock.now();
+        assert!(system <= UTC::now() && system > UTC::now() - Duration::seconds(10));
+    }
+}
@@ -0,0 +1,191 @@
+//! A single error type covering everything that may go wrong while obtaining a token.
+
+use hyper;
+use hyper::status::StatusCode;
+use serde_json;
+
+use std::error;
+use std::fmt;
+use std::io;
+use std::time::Duration;
+
+use types::{JsonError, RequestError};
+
+// The error code of a refresh token or authorization code that is invalid, expired or revoked.
+pub const INVALID_GRANT: &'static str = "invalid_grant";
+
+/// Encapsulates all errors which may occur when talking to an OAuth server.
+///
+/// Conversions from the underlying error types are provided, so that `try!` works on any of
+/// them within functions returning this type.
+#[derive(Debug)]
+pub enum Error {
+    /// Indicates connection failure
+    HttpError(hyper::Error),
+    /// The server answered with an unexpected HTTP status
+    BadStatus(StatusCode),
+    /// The server is rate limiting us (HTTP status 429), and asked to wait for the given
+    /// duration before trying again; zero if it didn't say
+    RateLimited(Duration),
+    /// The OAuth server refused the request. The first string is the error code
+    /// (like `invalid_grant`), the second may be a more detailed description
+    AuthError(String, Option<String>),
+    /// The server's answer could not be parsed
+    JsonError(serde_json::Error),
+    /// The server's answer could not be understood; contains the reason and a part of the
+    /// answer
+    InvalidResponse(String),
+    /// Reading or writing local data failed
+    IoError(io::Error),
+    /// An OpenID Connect ID token could not be decoded, or failed verification
+    InvalidIdToken(String),
+    /// Credentials would have been sent to the contained URI, which doesn't use HTTPS. See the
+    /// `with_insecure_http()` methods of the flows to allow that anyway, e.g. for test servers.
+    ///
+    /// Not all endpoints are checked: `ServiceAccountAccess` uses the token URI of its key as
+    /// is, and a `DeviceFlow` used on its own doesn't check its URIs either (the
+    /// `Authenticator` does so for the device flows it runs). The endpoints given to
+    /// `Authenticator::revoke_token()` and `Authenticator::introspect_token()` are checked
+    InsecureUri(String),
+}
+
+impl Error {
+    /// Whether the server refused the grant with `invalid_grant`, e.g. because the refresh token
+    /// expired or was revoked. Retrying won't help then; the user has to authorize again, with a
+    /// new `DeviceFlow` or `InstalledFlow`.
+    pub fn needs_reauth(&self) -> bool {
+        match *self {
+            Error::AuthError(ref error, _) => error == INVALID_GRANT,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            Error::HttpError(ref err) => err.fmt(f),
+            Error::BadStatus(ref status) => write!(f, "Unexpected server response: {}", status),
+            Error::RateLimited(ref delay) => {
+                write!(f, "Rate limited by the server, retry after {}s", delay.as_secs())
+            }
+            Error::AuthError(ref error, ref desc) => {
+                try!(error.fmt(f));
+                if let Some(ref desc) = *desc {
+                    try!(write!(f, ": {}", desc));
+                }
+                Ok(())
+            }
+            Error::JsonError(ref err) => write!(f, "Invalid server response: {}", err),
+            Error::InvalidResponse(ref reason) => write!(f, "Invalid server response: {}", reason),
+            Error::IoError(ref err) => err.fmt(f),
+            Error::InvalidIdToken(ref reason) => write!(f, "Invalid ID token: {}", reason),
+            Error::InsecureUri(ref uri) => {
+                write!(f, "Refusing to send credentials over plain HTTP to {}", uri)
+            }
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::HttpError(_) => "connection failure",
+            Error::BadStatus(_) => "unexpected server response",
+            Error::RateLimited(_) => "rate limited",
+            Error::AuthError(ref error, _) => error,
+            Error::JsonError(_) | Error::InvalidResponse(_) => "invalid server response",
+            Error::IoError(_) => "io error",
+            Error::InvalidIdToken(_) => "invalid ID token",
+            Error::InsecureUri(_) => "insecure endpoint",
+        }
+    }
+
+    fn source(&self) -> Option<&(error::Error + 'static)> {
+        match *self {
+            Error::HttpError(ref err) => Some(err),
+            Error::JsonError(ref err) => Some(err),
+            Error::IoError(ref err) => Some(err),
+            Error::BadStatus(_) |
+            Error::RateLimited(_) |
+            Error::AuthError(_, _) |
+            Error::InvalidResponse(_) |
+            Error::InvalidIdToken(_) |
+            Error::InsecureUri(_) => None,
+        }
+    }
+}
+
+impl From<hyper::Error> for Error {
+    fn from(value: hyper::Error) -> Error {
+        Error::HttpError(value)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(value: serde_json::Error) -> Error {
+        Error::JsonError(value)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(value: io::Error) -> Error {
+        Error::IoError(value)
+    }
+}
+
+impl From<JsonError> for Error {
+    fn from(value: JsonError) -> Error {
+        Error::AuthError(value.error, value.error_description)
+    }
+}
+
+impl From<RequestError> for Error {
+    fn from(value: RequestError) -> Error {
+        match value {
+            RequestError::HttpError(err) => Error::HttpError(err),
+            RequestError::InvalidClient => Error::AuthError("invalid_client".to_string(), None),
+            RequestError::InvalidScope(desc) => {
+                Error::AuthError("invalid_scope".to_string(), Some(desc))
+            }
+            RequestError::NegativeServerResponse(error, desc) => Error::AuthError(error, desc),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error as StdError;
+
+    fn parse_error() -> Result<serde_json::Value, Error> {
+        Ok(try!(serde_json::from_str("{")))
+    }
+
+    #[test]
+    fn conversions() {
+        match parse_error() {
+            Err(err @ Error::JsonError(_)) => assert!(err.source().is_some()),
+            r => panic!("Expected a JsonError, got {:?}", r),
+        }
+
+        let err = Error::from(io::Error::new(io::ErrorKind::NotFound, "no such file"));
+        assert_eq!(err.to_string(), "no such file");
+        assert_eq!(err.source().unwrap().to_string(), "no such file");
+    }
+
+    #[test]
+    fn auth_error() {
+        let err = Error::from(RequestError::NegativeServerResponse("invalid_grant".to_string(),
+                                                                   Some("Bad Request"
+                                                                       .to_string())));
+        assert_eq!(err.to_string(), "invalid_grant: Bad Request");
+        assert!(err.source().is_none());
+        assert!(err.needs_reauth());
+
+        let err = Error::from(RequestError::InvalidClient);
+        assert_eq!(err.to_string(), "invalid_client");
+        assert!(!err.needs_reauth());
+        assert!(!Error::RateLimited(Duration::from_secs(30)).needs_reauth());
+    }
+}
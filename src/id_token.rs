@@ -0,0 +1,428 @@
+//! Verification of OpenID Connect ID tokens, as returned alongside access tokens when the
+//! `openid` scope is requested.
+//!
+//! `Token::decode_id_token_claims()` merely decodes an ID token; `IdTokenVerifier` additionally
+//! checks its RS256 signature against the provider's published keys (JWKS), as well as the
+//! `iss`, `aud` and `exp` claims.
+//!
+//! Resources:
+//! - [Validating an ID
+//! token](https://developers.google.com/identity/protocols/OpenIDConnect#validatinganidtoken)
+
+use std::borrow::BorrowMut;
+use std::cmp::max;
+use std::io::Read;
+use std::sync::Arc;
+
+use base64;
+use chrono::{self, DateTime, UTC};
+use hyper;
+use hyper::header::{CacheControl, CacheDirective};
+use ring::signature;
+use serde_json as json;
+use untrusted;
+
+use clock::{Clock, SystemClock};
+use error::Error;
+use types::deserialize_audience;
+
+/// The URL at which Google publishes the keys its ID tokens are signed with.
+pub const GOOGLE_JWKS_URL: &'static str = "https://www.googleapis.com/oauth2/v3/certs";
+
+const GOOGLE_ISSUERS: [&'static str; 2] = ["accounts.google.com", "https://accounts.google.com"];
+
+// However many tokens with unknown key ids arrive, the keys are fetched at most this often.
+// They are cached at least as long, too, even if the JWKS response allows no caching at all.
+const MIN_REFETCH_INTERVAL_SECS: i64 = 60;
+
+/// The claims of an ID token which passed verification.
+///
+/// Only the standard claims are represented here; use `Token::decode_id_token_claims()` to
+/// access any others.
+#[derive(Deserialize, Serialize, Clone, PartialEq, Debug)]
+pub struct IdTokenClaims {
+    /// The issuer of the token
+    pub iss: String,
+    /// The identifier of the user, unique and never reused
+    pub sub: String,
+    /// The client ids the token was issued to; usually just one, but tokens may name several
+    #[serde(deserialize_with = "deserialize_audience")]
+    pub aud: Vec<String>,
+    /// Expiry, in seconds since the epoch
+    pub exp: i64,
+    /// Time of issue, in seconds since the epoch
+    pub iat: i64,
+    pub email: Option<String>,
+    pub email_verified: Option<bool>,
+    pub name: Option<String>,
+    pub picture: Option<String>,
+    pub nonce: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct JwtHeader {
+    alg: String,
+    kid: Option<String>,
+}
+
+#[derive(Deserialize, Clone)]
+struct Jwk {
+    kid: String,
+    kty: String,
+    n: Option<String>,
+    e: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Jwks {
+    keys: Vec<Jwk>,
+}
+
+/// Verifies ID tokens issued to one client.
+///
+/// The provider's keys are fetched on first use and cached for as long as the `max-age` of the
+/// JWKS response allows, but at least a minute. A token signed with a key that is not in the
+/// cache causes the keys to be fetched again, so that key rotations are picked up right away; to
+/// not be made to fetch them for every forged token, this happens at most once a minute. Other
+/// tokens with unknown keys are rejected in between.
+pub struct IdTokenVerifier<C> {
+    client: C,
+    audience: String,
+    issuers: Vec<String>,
+    jwks_uri: String,
+    keys: Vec<Jwk>,
+    keys_fetched_at: Option<DateTime<UTC>>,
+    keys_expire_at: Option<DateTime<UTC>>,
+    clock: Arc<Clock>,
+}
+
+impl<C> IdTokenVerifier<C>
+    where C: BorrowMut<hyper::Client>
+{
+    /// Create a verifier accepting tokens issued by Google to `client_id`.
+    pub fn new(client: C, client_id: &str) -> IdTokenVerifier<C> {
+        IdTokenVerifier {
+            client: client,
+            audience: client_id.to_string(),
+            issuers: GOOGLE_ISSUERS.iter().map(|s| s.to_string()).collect(),
+            jwks_uri: GOOGLE_JWKS_URL.to_string(),
+            keys: Vec::new(),
+            keys_fetched_at: None,
+            keys_expire_at: None,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Fetch the signing keys from `jwks_uri` instead of Google's.
+    pub fn with_jwks_uri(mut self, jwks_uri: String) -> IdTokenVerifier<C> {
+        self.jwks_uri = jwks_uri;
+        self.keys_expire_at = None;
+        self
+    }
+
+    /// Accept tokens whose `iss` claim is one of `issuers` instead of Google's.
+    pub fn with_issuers(mut self, issuers: Vec<String>) -> IdTokenVerifier<C> {
+        self.issuers = issuers;
+        self
+    }
+
+    /// Ask `clock` for the current time, against which the `exp` claim and the age of the
+    /// cached keys are checked. By default, the system time is used.
+    pub fn with_clock(mut self, clock: Arc<Clock>) -> IdTokenVerifier<C> {
+        self.clock = clock;
+        self
+    }
+
+    /// Verify `id_token` and return its claims.
+    ///
+    /// Fails with `Error::InvalidIdToken` if the token is malformed, its signature does not
+    /// match, or it was issued by someone else, to someone else, or has expired.
+    pub fn verify(&mut self, id_token: &str) -> Result<IdTokenClaims, Error> {
+        let parts: Vec<&str> = id_token.split('.').collect();
+        if parts.len() != 3 {
+            return Err(invalid("not a JWT".to_string()));
+        }
+
+        let header: JwtHeader = try!(json::from_slice(&try!(decode(parts[0]))));
+        if header.alg != "RS256" {
+            return Err(invalid(format!("unsupported algorithm {}", header.alg)));
+        }
+        let kid = match header.kid {
+            Some(kid) => kid,
+            None => return Err(invalid("no key id in header".to_string())),
+        };
+
+        let (n, e) = try!(self.public_key(&kid));
+        let sig = try!(decode(parts[2]));
+        let signed = &id_token[..parts[0].len() + 1 + parts[1].len()];
+        try!(signature::primitive::verify_rsa(&signature::RSA_PKCS1_2048_8192_SHA256,
+                                              (untrusted::Input::from(&n),
+                                               untrusted::Input::from(&e)),
+                                              untrusted::Input::from(signed.as_bytes()),
+                                              untrusted::Input::from(&sig))
+            .map_err(|_| invalid("signature mismatch".to_string())));
+
+        let claims: IdTokenClaims = try!(json::from_slice(&try!(decode(parts[1]))));
+        if !self.issuers.contains(&claims.iss) {
+            return Err(invalid(format!("unexpected issuer {}", claims.iss)));
+        }
+        if !claims.aud.contains(&self.audience) {
+            return Err(invalid(format!("issued to {}", claims.aud.join(", "))));
+        }
+        if claims.exp <= self.clock.now().timestamp() {
+            return Err(invalid("expired".to_string()));
+        }
+        Ok(claims)
+    }
+
+    // Returns modulus and exponent of the RSA key with id `kid`.
+    fn public_key(&mut self, kid: &str) -> Result<(Vec<u8>, Vec<u8>), Error> {
+        let now = self.clock.now();
+        let fresh = match self.keys_expire_at {
+            Some(t) => t > now,
+            None => false,
+        };
+        let may_refetch = match self.keys_fetched_at {
+            Some(t) => t + chrono::Duration::seconds(MIN_REFETCH_INTERVAL_SECS) <= now,
+            None => true,
+        };
+        if !fresh || (may_refetch && !self.keys.iter().any(|k| k.kid == kid)) {
+            try!(self.fetch_keys());
+        }
+
+        match self.keys.iter().find(|k| k.kid == kid && k.kty == "RSA") {
+            Some(&Jwk { n: Some(ref n), e: Some(ref e), .. }) => {
+                Ok((try!(decode(n)), try!(decode(e))))
+            }
+            _ => Err(invalid(format!("unknown key {}", kid))),
+        }
+    }
+
+    fn fetch_keys(&mut self) -> Result<(), Error> {
+        debug!("Fetching ID token keys from {}", self.jwks_uri);
+        let mut res = try!(self.client.borrow_mut().get(&self.jwks_uri).send());
+        if !res.status.is_success() {
+            return Err(Error::BadStatus(res.status));
+        }
+
+        let max_age = res.headers
+            .get::<CacheControl>()
+            .and_then(|cc| {
+                cc.iter()
+                    .filter_map(|d| match *d {
+                        CacheDirective::MaxAge(secs) => Some(secs),
+                        _ => None,
+                    })
+                    .next()
+            })
+            .unwrap_or(0);
+
+        let mut body = String::new();
+        try!(res.read_to_string(&mut body));
+        let jwks: Jwks = try!(json::from_str(&body));
+
+        let now = self.clock.now();
+        self.keys = jwks.keys;
+        self.keys_fetched_at = Some(now);
+        let lifetime = max(max_age as i64, MIN_REFETCH_INTERVAL_SECS);
+        self.keys_expire_at = Some(now + chrono::Duration::seconds(lifetime));
+        Ok(())
+    }
+}
+
+fn invalid(reason: String) -> Error {
+    Error::InvalidIdToken(reason)
+}
+
+fn decode(s: &str) -> Result<Vec<u8>, Error> {
+    base64::decode_config(s, base64::URL_SAFE_NO_PAD)
+        .map_err(|e| invalid(format!("bad encoding: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::UTC;
+    use hyper;
+    use rustls;
+    use rustls::internal::pemfile;
+    use rustls::sign::{RSASigner, Signer};
+    use serde_json as json;
+
+    use helper::service_account_key_from_file;
+    use refresh::tests::RecordingConnector;
+
+    const KID: &'static str = "0c4fffc10a02b3a700d6c17e2a51fbabada8c27d";
+    const CLIENT_ID: &'static str = "14070749909-vgip2f1okm7bkvajhi9jugan6126io9v.apps.\
+                                     googleusercontent.com";
+
+    fn jwks_reply() -> String {
+        format!("HTTP/1.1 200 OK\r\n\
+                 Cache-Control: public, max-age=3600\r\n\
+                 Content-Type: application/json\r\n\
+                 \r\n\
+                 {{\"keys\": [{{\"kty\": \"RSA\", \"alg\": \"RS256\", \"use\": \"sig\", \
+                 \"kid\": \"{}\", \"e\": \"AQAB\", \"n\": \"19Yf1IkmxwX93OS94QG-EFGef5y_Qk2NdZvLl5lgBmgIY5RO779eY02sYYypvkaXyVt7IWWqNJcUiYGsmGPFG-Ssd56T306H3_zf7AVe0ZmeBWG-m9V7GaY08uAbdS6UbUqvy-JR_m6DxggXSb-7sCZHQU0TwSUZ8p5aTTq3idPH6PNBuqmkJ9mQNz_GLrKJyEu9oVvrXZ4a66cb2yCU_zTdnRX1V8bNypC-kLtxbmqrxAEanY7jznYw5A0cAOIH05WoBj7SVUOIFJeq4F0U19qNIhi1yMXoR3y622D-VDo636QIk0XX_hNx6OSiabNPTKx5Oclc2rihFGrifTPmtw\"}}]}}",
+                KID)
+    }
+
+    fn encode<T: AsRef<[u8]>>(s: T) -> String {
+        base64::encode_config(s.as_ref(), base64::URL_SAFE_NO_PAD)
+    }
+
+    // Signs a JWT with the test service account's key, which the JWKS above belongs to.
+    fn sign(kid: &str, claims: &IdTokenClaims) -> String {
+        let key = service_account_key_from_file(&"examples/Sanguine-69411a0c0eea.json"
+                .to_string())
+            .unwrap();
+        let pem = key.private_key.unwrap();
+        let mut reader: &[u8] = pem.as_bytes();
+        let private_key = pemfile::pkcs8_private_keys(&mut reader).unwrap().remove(0);
+        let signer = RSASigner::new(&private_key).unwrap();
+
+        let mut jwt = encode(format!("{{\"alg\":\"RS256\",\"kid\":\"{}\",\"typ\":\"JWT\"}}", kid));
+        jwt.push('.');
+        jwt.push_str(&encode(json::to_string(claims).unwrap()));
+        let sig = signer.sign(rustls::SignatureScheme::RSA_PKCS1_SHA256, jwt.as_bytes()).unwrap();
+        jwt.push('.');
+        jwt.push_str(&encode(sig));
+        jwt
+    }
+
+    fn claims(aud: &str, exp: i64) -> IdTokenClaims {
+        IdTokenClaims {
+            iss: "https://accounts.google.com".to_string(),
+            sub: "110169484474386276334".to_string(),
+            aud: vec![aud.to_string()],
+            exp: exp,
+            iat: exp - 3600,
+            email: Some("user@example.com".to_string()),
+            email_verified: Some(true),
+            name: None,
+            picture: None,
+            nonce: None,
+        }
+    }
+
+    fn verifier(connector: RecordingConnector) -> IdTokenVerifier<hyper::Client> {
+        IdTokenVerifier::new(hyper::Client::with_connector(connector), CLIENT_ID)
+            .with_jwks_uri("https://example.com/certs".to_string())
+    }
+
+    fn expect_invalid(result: Result<IdTokenClaims, Error>) {
+        match result {
+            Err(Error::InvalidIdToken(_)) => {}
+            r => panic!("Expected InvalidIdToken, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn verify_valid_token() {
+        let connector = RecordingConnector::new(&[&jwks_reply()]);
+        let requests = connector.requests.clone();
+        let mut verifier = verifier(connector);
+        let exp = UTC::now().timestamp() + 3600;
+
+        let result = verifier.verify(&sign(KID, &claims(CLIENT_ID, exp))).unwrap();
+        assert_eq!(result, claims(CLIENT_ID, exp));
+
+        // The keys are cached according to max-age.
+        verifier.verify(&sign(KID, &claims(CLIENT_ID, exp))).unwrap();
+        let requests = requests.lock().unwrap().clone();
+        assert_eq!(requests.len(), 1);
+        assert!(requests[0].starts_with("GET /certs HTTP/1.1\r\n"));
+    }
+
+    #[test]
+    fn verify_rejects_tampered_token() {
+        let mut verifier = verifier(RecordingConnector::new(&[&jwks_reply()]));
+        let exp = UTC::now().timestamp() + 3600;
+        let token = sign(KID, &claims(CLIENT_ID, exp));
+        let forged = encode(json::to_string(&claims(CLIENT_ID, exp + 3600)).unwrap());
+        let parts: Vec<&str> = token.split('.').collect();
+
+        expect_invalid(verifier.verify(&format!("{}.{}.{}", parts[0], forged, parts[2])));
+        expect_invalid(verifier.verify("not.a-token"));
+    }
+
+    #[test]
+    fn verify_checks_claims() {
+        let mut verifier = verifier(RecordingConnector::new(&[&jwks_reply()]));
+        let now = UTC::now().timestamp();
+
+        expect_invalid(verifier.verify(&sign(KID, &claims("someone-else", now + 3600))));
+        expect_invalid(verifier.verify(&sign(KID, &claims(CLIENT_ID, now - 10))));
+    }
+
+    #[test]
+    fn verify_accepts_list_of_audiences() {
+        let mut verifier = verifier(RecordingConnector::new(&[&jwks_reply()]));
+        let exp = UTC::now().timestamp() + 3600;
+        let mut both = claims(CLIENT_ID, exp);
+        both.aud = vec!["other-client".to_string(), CLIENT_ID.to_string()];
+
+        assert_eq!(verifier.verify(&sign(KID, &both)).unwrap().aud, both.aud);
+        both.aud.remove(1);
+        expect_invalid(verifier.verify(&sign(KID, &both)));
+
+        let single: IdTokenClaims = json::from_str(r#"{"iss":"accounts.google.com","sub":"1",
+            "aud":"client","exp":0,"iat":0,"email":null,"email_verified":null,"name":null,
+            "picture":null,"nonce":null}"#)
+            .unwrap();
+        assert_eq!(single.aud, vec!["client".to_string()]);
+    }
+
+    #[test]
+    fn verify_unknown_key() {
+        // An unknown key id causes the keys to be fetched again, but not over and over.
+        let connector = RecordingConnector::new(&[&jwks_reply(), &jwks_reply()]);
+        let requests = connector.requests.clone();
+        let mut verifier = verifier(connector);
+        let token = sign("rotated", &claims(CLIENT_ID, UTC::now().timestamp() + 3600));
+
+        expect_invalid(verifier.verify(&token));
+        assert_eq!(requests.lock().unwrap().len(), 1);
+        expect_invalid(verifier.verify(&token));
+        expect_invalid(verifier.verify(&sign("made-up",
+                                             &claims(CLIENT_ID, UTC::now().timestamp() + 3600))));
+        assert_eq!(requests.lock().unwrap().len(), 1);
+
+        // Once the minimum interval has passed, an unknown key id is looked up again.
+        verifier.keys_fetched_at = Some(UTC::now() -
+                                        chrono::Duration::seconds(MIN_REFETCH_INTERVAL_SECS));
+        expect_invalid(verifier.verify(&token));
+        assert_eq!(requests.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn verify_caches_keys_without_max_age() {
+        let reply = jwks_reply().replace("Cache-Control: public, max-age=3600\r\n", "");
+        let connector = RecordingConnector::new(&[&reply, &reply]);
+        let requests = connector.requests.clone();
+        let mut verifier = verifier(connector);
+        let exp = UTC::now().timestamp() + 3600;
+
+        verifier.verify(&sign(KID, &claims(CLIENT_ID, exp))).unwrap();
+        // Forged tokens don't get the keys fetched for each of them.
+        for kid in &["made-up", "also-made-up", "rotated"] {
+            expect_invalid(verifier.verify(&sign(kid, &claims(CLIENT_ID, exp))));
+        }
+        verifier.verify(&sign(KID, &claims(CLIENT_ID, exp))).unwrap();
+        assert_eq!(requests.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn verify_with_clock() {
+        use clock::MockClock;
+
+        let clock = Arc::new(MockClock::new(UTC::now() - chrono::Duration::days(1)));
+        let mut verifier = verifier(RecordingConnector::new(&[&jwks_reply()]))
+            .with_clock(clock.clone());
+        let token = sign(KID, &claims(CLIENT_ID, clock.now().timestamp() + 60));
+
+        // Expired by the system time, but not by the clock.
+        verifier.verify(&token).unwrap();
+        clock.advance(chrono::Duration::seconds(60));
+        expect_invalid(verifier.verify(&token));
+    }
+}
@@ -10,17 +10,23 @@ use std::convert::AsRef;
 use std::error::Error;
 use std::io;
 use std::io::Read;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 use std::sync::mpsc::{channel, Receiver, Sender};
+use std::time::Duration;
 
+use base64;
 use hyper;
 use hyper::{client, header, server, status, uri};
-use serde_json::error;
+use ring::digest;
+use ring::rand::{SecureRandom, SystemRandom};
 use url::form_urlencoded;
 use url::percent_encoding::{percent_encode, QUERY_ENCODE_SET};
 
-use types::{ApplicationSecret, Token};
+use types::{ensure_https, ApplicationSecret, Token, DEFAULT_USER_AGENT};
 use authenticator_delegate::AuthenticatorDelegate;
+use browser::{BrowserOpener, NoBrowser};
+use clock::{Clock, SystemClock};
+use refresh::token_from_body;
 
 const OOB_REDIRECT_URI: &'static str = "urn:ietf:wg:oauth:2.0:oob";
 
@@ -30,7 +36,9 @@ const OOB_REDIRECT_URI: &'static str = "urn:ietf:wg:oauth:2.0:oob";
 fn build_authentication_request_url<'a, T, I>(auth_uri: &str,
                                               client_id: &str,
                                               scopes: I,
-                                              redirect_uri: Option<String>)
+                                              redirect_uri: Option<String>,
+                                              pkce: Option<&Pkce>,
+                                              options: &AuthorizationOptions)
                                               -> String
     where T: AsRef<str> + 'a,
           I: IntoIterator<Item = &'a T>
@@ -45,22 +53,120 @@ fn build_authentication_request_url<'a, T, I>(auth_uri: &str,
     scopes_string.pop();
 
     url.push_str(auth_uri);
-    vec![format!("?scope={}", scopes_string),
-         format!("&redirect_uri={}",
-                 redirect_uri.unwrap_or(OOB_REDIRECT_URI.to_string())),
-         format!("&response_type=code"),
-         format!("&client_id={}", client_id)]
-        .into_iter()
+    let mut params = vec![format!("?scope={}", scopes_string),
+                          format!("&redirect_uri={}",
+                                  redirect_uri.unwrap_or(OOB_REDIRECT_URI.to_string())),
+                          format!("&response_type=code"),
+                          format!("&client_id={}", client_id)];
+    if let Some(pkce) = pkce {
+        params.push(format!("&code_challenge={}", pkce.challenge()));
+        params.push(format!("&code_challenge_method={}", pkce.challenge_method()));
+    }
+    if let Some(ref access_type) = options.access_type {
+        params.push(format!("&access_type={}", access_type));
+    }
+    // These come from the user, and may contain characters like `+` and `&`, which have to be
+    // encoded as well.
+    if let Some(ref prompt) = options.prompt {
+        params.push(format!("&{}", form_urlencoded::serialize(&[("prompt", prompt)])));
+    }
+    if let Some(ref login_hint) = options.login_hint {
+        params.push(format!("&{}", form_urlencoded::serialize(&[("login_hint", login_hint)])));
+    }
+    if options.include_granted_scopes {
+        params.push("&include_granted_scopes=true".to_string());
+    }
+    params.into_iter()
         .fold(url, |mut u, param| {
             u.push_str(&percent_encode(param.as_ref(), QUERY_ENCODE_SET));
             u
         })
 }
 
+// Optional parameters of the authorization request, set with the `with_*()` methods of
+// `InstalledFlow`.
+#[derive(Clone, Debug)]
+struct AuthorizationOptions {
+    access_type: Option<String>,
+    prompt: Option<String>,
+    login_hint: Option<String>,
+    include_granted_scopes: bool,
+}
+
+impl Default for AuthorizationOptions {
+    fn default() -> AuthorizationOptions {
+        AuthorizationOptions {
+            // Without it, Google hands out no refresh token.
+            access_type: Some("offline".to_string()),
+            prompt: None,
+            login_hint: None,
+            include_granted_scopes: false,
+        }
+    }
+}
+
+/// A [PKCE](https://tools.ietf.org/html/rfc7636) code verifier, together with the `S256`
+/// challenge derived from it.
+///
+/// The challenge is sent along with the authorization request, the verifier with the token
+/// request. This proves to the server that both come from the same client, which matters for
+/// installed applications, as their client secret isn't actually secret.
+#[derive(Clone, Debug)]
+pub struct Pkce {
+    verifier: String,
+    challenge: String,
+}
+
+impl Pkce {
+    /// Generates a new, random code verifier.
+    pub fn new() -> io::Result<Pkce> {
+        let mut bytes = [0u8; 32];
+        try!(SystemRandom::new()
+            .fill(&mut bytes)
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "Failed to obtain random bytes")));
+        Ok(Pkce::from_verifier(base64::encode_config(&bytes, base64::URL_SAFE_NO_PAD)))
+    }
+
+    /// Uses the given code verifier, which must consist of 43 to 128 characters out of
+    /// `[A-Za-z0-9-._~]`.
+    pub fn from_verifier(verifier: String) -> Pkce {
+        let challenge = base64::encode_config(digest::digest(&digest::SHA256, verifier.as_bytes())
+                                                  .as_ref(),
+                                              base64::URL_SAFE_NO_PAD);
+        Pkce {
+            verifier: verifier,
+            challenge: challenge,
+        }
+    }
+
+    /// The secret code verifier, to be sent with the token request
+    pub fn verifier(&self) -> &str {
+        &self.verifier
+    }
+
+    /// The code challenge, to be sent with the authorization request
+    pub fn challenge(&self) -> &str {
+        &self.challenge
+    }
+
+    /// How the challenge was derived from the verifier; always `S256`
+    pub fn challenge_method(&self) -> &'static str {
+        "S256"
+    }
+}
+
 pub struct InstalledFlow<C> {
     client: C,
     server: Option<server::Listening>,
     port: Option<u32>,
+    redirect_timeout: Option<Duration>,
+    pkce: Option<Pkce>,
+    user_agent: String,
+    headers: header::Headers,
+    insecure_http: bool,
+    options: AuthorizationOptions,
+    browser: Arc<BrowserOpener>,
+    clock: Arc<Clock>,
 
     auth_code_rcv: Option<Receiver<String>>,
 }
@@ -72,7 +178,7 @@ pub enum InstalledFlowReturnMethod {
     Interactive,
     /// Involves spinning up a local HTTP server and Google redirecting the browser to
     /// the server with a URL containing the code (preferred, but not as reliable). The
-    /// parameter is the port to listen on.
+    /// parameter is the port to listen on; with 0, a free port is chosen.
     HTTPRedirect(u32),
 }
 
@@ -87,6 +193,14 @@ impl<C> InstalledFlow<C>
             client: client,
             server: None,
             port: None,
+            redirect_timeout: None,
+            pkce: None,
+            user_agent: DEFAULT_USER_AGENT.to_string(),
+            headers: header::Headers::new(),
+            insecure_http: false,
+            options: AuthorizationOptions::default(),
+            browser: Arc::new(NoBrowser),
+            clock: Arc::new(SystemClock),
             auth_code_rcv: None,
         };
         match method {
@@ -106,10 +220,20 @@ impl<C> InstalledFlow<C>
                         match listening {
                             Result::Err(_) => default,
                             Result::Ok(listening) => {
+                                // The port actually bound to, in case a free one was picked.
+                                let port = listening.socket.port() as u32;
                                 InstalledFlow {
                                     client: default.client,
                                     server: Some(listening),
                                     port: Some(port),
+                                    redirect_timeout: None,
+                                    pkce: None,
+                                    user_agent: default.user_agent,
+                                    headers: default.headers,
+                                    insecure_http: false,
+                                    options: AuthorizationOptions::default(),
+                                    browser: default.browser,
+                                    clock: default.clock,
                                     auth_code_rcv: Some(rx),
                                 }
                             }
@@ -120,6 +244,85 @@ impl<C> InstalledFlow<C>
         }
     }
 
+    /// Open the authorization URL with `opener`, e.g. a `DesktopBrowser`, before presenting it
+    /// through the `AuthenticatorDelegate`. By default, nothing is opened.
+    pub fn with_browser_opener(mut self, opener: Arc<BrowserOpener>) -> InstalledFlow<C> {
+        self.browser = opener;
+        self
+    }
+
+    /// Ask `clock` for the current time, from which the expiry of the token is computed.
+    /// By default, the system time is used.
+    pub fn with_clock(mut self, clock: Arc<Clock>) -> InstalledFlow<C> {
+        self.clock = clock;
+        self
+    }
+
+    /// Give up waiting for the browser to be redirected to our local server after `timeout`.
+    /// By default, we wait forever. Has no effect on the interactive method.
+    pub fn with_redirect_timeout(mut self, timeout: Duration) -> InstalledFlow<C> {
+        self.redirect_timeout = Some(timeout);
+        self
+    }
+
+    /// Protect the authorization code with `pkce`, which should be freshly generated with
+    /// `Pkce::new()`. By default, PKCE is not used.
+    pub fn with_pkce(mut self, pkce: Pkce) -> InstalledFlow<C> {
+        self.pkce = Some(pkce);
+        self
+    }
+
+    /// Send `user_agent` as `User-Agent` header, instead of `DEFAULT_USER_AGENT`.
+    pub fn with_user_agent(mut self, user_agent: String) -> InstalledFlow<C> {
+        self.user_agent = user_agent;
+        self
+    }
+
+    /// Add `headers` to the request exchanging the authorization code for a token; the browser
+    /// visiting the `auth_uri` can't be made to send any. `Content-Type`, `Accept` and
+    /// `User-Agent` are always the flow's own.
+    pub fn with_headers(mut self, headers: header::Headers) -> InstalledFlow<C> {
+        self.headers = headers;
+        self
+    }
+
+    /// Send `access_type` with the authorization request; Google knows `online` and `offline`.
+    /// By default, `offline` is sent, as Google only hands out refresh tokens then; with `None`,
+    /// the parameter is left out, which may suit other providers better.
+    pub fn with_access_type(mut self, access_type: Option<&str>) -> InstalledFlow<C> {
+        self.options.access_type = access_type.map(|a| a.to_string());
+        self
+    }
+
+    /// Send `prompt` with the authorization request, e.g. `consent` to make Google ask the user
+    /// again, and hand out a new refresh token even if it did before. Several values are
+    /// separated by spaces. By default, none is sent.
+    pub fn with_prompt(mut self, prompt: &str) -> InstalledFlow<C> {
+        self.options.prompt = Some(prompt.to_string());
+        self
+    }
+
+    /// Suggest the account to sign in with, usually by its email address.
+    pub fn with_login_hint(mut self, login_hint: &str) -> InstalledFlow<C> {
+        self.options.login_hint = Some(login_hint.to_string());
+        self
+    }
+
+    /// Ask Google to include the scopes the user granted before in the new token, for
+    /// [incremental authorization](https://developers.google.com/identity/protocols/OAuth2WebServer#incrementalAuth).
+    pub fn with_include_granted_scopes(mut self, include: bool) -> InstalledFlow<C> {
+        self.options.include_granted_scopes = include;
+        self
+    }
+
+    /// Allow an `auth_uri` or `token_uri` which doesn't use HTTPS, e.g. of a local test server.
+    /// By default, `obtain_token()` refuses them with `Error::InsecureUri`, before the user is
+    /// asked anything; the `http://localhost` redirect back to our own server is always allowed.
+    pub fn with_insecure_http(mut self, allow: bool) -> InstalledFlow<C> {
+        self.insecure_http = allow;
+        self
+    }
+
     /// Handles the token request flow; it consists of the following steps:
     /// . Obtain a auhorization code with user cooperation or internal redirect.
     /// . Obtain a token and refresh token using that code.
@@ -134,30 +337,12 @@ impl<C> InstalledFlow<C>
         where T: AsRef<str> + 'a,
               S: Iterator<Item = &'a T>
     {
-        let authcode = try!(self.get_authorization_code(auth_delegate, &appsecret, scopes));
-        let tokens = try!(self.request_token(&appsecret, &authcode));
-
-        // Successful response
-        if tokens.access_token.is_some() {
-            let mut token = Token {
-                access_token: tokens.access_token.unwrap(),
-                refresh_token: tokens.refresh_token.unwrap(),
-                token_type: tokens.token_type.unwrap(),
-                expires_in: tokens.expires_in,
-                expires_in_timestamp: None,
-            };
-
-            token.set_expiry_absolute();
-            Result::Ok(token)
-        } else {
-            let err = io::Error::new(io::ErrorKind::Other,
-                                     format!("Token API error: {} {}",
-                                             tokens.error.unwrap_or("<unknown err>".to_string()),
-                                             tokens.error_description
-                                                 .unwrap_or("".to_string()))
-                                         .as_str());
-            Result::Err(Box::new(err))
+        if !self.insecure_http {
+            try!(ensure_https(&appsecret.auth_uri));
+            try!(ensure_https(&appsecret.token_uri));
         }
+        let authcode = try!(self.get_authorization_code(auth_delegate, &appsecret, scopes));
+        self.request_token(&appsecret, &authcode)
     }
 
     /// Obtains an authorization code either interactively or via HTTP redirect (see
@@ -175,7 +360,10 @@ impl<C> InstalledFlow<C>
                 let url = build_authentication_request_url(&appsecret.auth_uri,
                                                            &appsecret.client_id,
                                                            scopes,
-                                                           None);
+                                                           None,
+                                                           self.pkce.as_ref(),
+                                                           &self.options);
+                self.open_browser(&url);
                 match auth_delegate.present_user_url(&url, true /* need_code */) {
                     None => {
                         Result::Err(Box::new(io::Error::new(io::ErrorKind::UnexpectedEof,
@@ -196,12 +384,26 @@ impl<C> InstalledFlow<C>
                                                            scopes,
                                                            Some(format!("http://localhost:{}",
                                                                         self.port
-                                                                            .unwrap_or(8080))));
+                                                                            .unwrap_or(8080))),
+                                                           self.pkce.as_ref(),
+                                                           &self.options);
+                self.open_browser(&url);
                 auth_delegate.present_user_url(&url, false /* need_code */);
 
-                match self.auth_code_rcv.as_ref().unwrap().recv() {
-                    Result::Err(e) => Result::Err(Box::new(e)),
-                    Result::Ok(s) => Result::Ok(s),
+                let auth_code_rcv = self.auth_code_rcv.as_ref().unwrap();
+                match self.redirect_timeout {
+                    None => {
+                        match auth_code_rcv.recv() {
+                            Result::Err(e) => Result::Err(Box::new(e)),
+                            Result::Ok(s) => Result::Ok(s),
+                        }
+                    }
+                    Some(timeout) => {
+                        match auth_code_rcv.recv_timeout(timeout) {
+                            Result::Err(e) => Result::Err(Box::new(e)),
+                            Result::Ok(s) => Result::Ok(s),
+                        }
+                    }
                 }
             }
         };
@@ -209,11 +411,18 @@ impl<C> InstalledFlow<C>
         result
     }
 
+    // The delegate presents the URL as well, so the user can go on without a browser.
+    fn open_browser(&self, url: &str) {
+        if let Err(err) = self.browser.open(url) {
+            warn!("Could not open {} in a browser: {}", url, err);
+        }
+    }
+
     /// Sends the authorization code to the provider in order to obtain access and refresh tokens.
     fn request_token(&mut self,
                      appsecret: &ApplicationSecret,
                      authcode: &str)
-                     -> Result<JSONTokenResponse, Box<Error>> {
+                     -> Result<Token, Box<Error>> {
         let redirect_uri;
 
         match self.port {
@@ -221,20 +430,24 @@ impl<C> InstalledFlow<C>
             Some(p) => redirect_uri = format!("http://localhost:{}", p),
         }
 
-        let body = form_urlencoded::serialize(vec![("code".to_string(), authcode.to_string()),
-                                                   ("client_id".to_string(),
-                                                    appsecret.client_id.clone()),
-                                                   ("client_secret".to_string(),
-                                                    appsecret.client_secret.clone()),
-                                                   ("redirect_uri".to_string(), redirect_uri),
-                                                   ("grant_type".to_string(),
-                                                    "authorization_code".to_string())]);
+        let mut params = vec![("code".to_string(), authcode.to_string()),
+                              ("client_id".to_string(), appsecret.client_id.clone()),
+                              ("client_secret".to_string(), appsecret.client_secret.clone()),
+                              ("redirect_uri".to_string(), redirect_uri),
+                              ("grant_type".to_string(), "authorization_code".to_string())];
+        if let Some(ref pkce) = self.pkce {
+            params.push(("code_verifier".to_string(), pkce.verifier().to_string()));
+        }
+        let body = form_urlencoded::serialize(params);
 
         let result: Result<client::Response, hyper::Error> = self.client
             .borrow_mut()
             .post(&appsecret.token_uri)
+            .headers(self.headers.clone())
             .body(&body)
             .header(header::ContentType("application/x-www-form-urlencoded".parse().unwrap()))
+            .header(header::Accept::json())
+            .header(header::UserAgent(self.user_agent.clone()))
             .send();
 
         let mut resp = String::new();
@@ -251,26 +464,10 @@ impl<C> InstalledFlow<C>
             }
         }
 
-        let token_resp: Result<JSONTokenResponse, error::Error> = serde_json::from_str(&resp);
-
-        match token_resp {
-            Result::Err(e) => return Result::Err(Box::new(e)),
-            Result::Ok(tok) => Result::Ok(tok) as Result<JSONTokenResponse, Box<Error>>,
-        }
+        Ok(try!(token_from_body(&resp, &*self.clock)))
     }
 }
 
-#[derive(Deserialize)]
-struct JSONTokenResponse {
-    access_token: Option<String>,
-    refresh_token: Option<String>,
-    token_type: Option<String>,
-    expires_in: Option<i64>,
-
-    error: Option<String>,
-    error_description: Option<String>,
-}
-
 /// HTTP handler handling the redirect from the provider.
 struct InstalledFlowHandler {
     auth_code_snd: Mutex<Sender<String>>,
@@ -323,25 +520,148 @@ impl InstalledFlowHandler {
 #[cfg(test)]
 mod tests {
     use super::build_authentication_request_url;
-    use super::InstalledFlowHandler;
+    use super::{AuthorizationOptions, InstalledFlow, InstalledFlowHandler, InstalledFlowReturnMethod,
+                Pkce};
 
-    use std::sync::Mutex;
+    use std::io;
+    use std::sync::{Arc, Mutex};
     use std::sync::mpsc::channel;
+    use std::time::Duration;
 
+    use authenticator_delegate::AuthenticatorDelegate;
+    use browser::BrowserOpener;
+    use helper::parse_application_secret;
+    use hyper;
     use hyper::Url;
+    use types::tests::TEST_APP_SECRET;
+
+    /// Plays the part of the user's browser, which is redirected to our server after the
+    /// user granted access.
+    struct RedirectingDelegate {
+        port: u32,
+        presented_url: Option<String>,
+    }
+
+    impl AuthenticatorDelegate for RedirectingDelegate {
+        fn present_user_url(&mut self, url: &String, need_code: bool) -> Option<String> {
+            assert!(!need_code);
+            self.presented_url = Some(url.clone());
+            hyper::Client::new()
+                .get(&format!("http://127.0.0.1:{}/?code=4/731fJ3BheyCouCniPufAd280GHNV5Ju35yYcGs",
+                              self.port))
+                .send()
+                .unwrap();
+            None
+        }
+    }
+
+    /// Never follows the URL it is shown.
+    struct IdleDelegate;
+
+    impl AuthenticatorDelegate for IdleDelegate {
+        fn present_user_url(&mut self, _: &String, _: bool) -> Option<String> {
+            None
+        }
+    }
+
+    #[derive(Default)]
+    struct RecordingOpener {
+        urls: Mutex<Vec<String>>,
+    }
+
+    impl BrowserOpener for RecordingOpener {
+        fn open(&self, url: &str) -> io::Result<()> {
+            self.urls.lock().unwrap().push(url.to_string());
+            Err(io::Error::new(io::ErrorKind::NotFound, "no browser in tests"))
+        }
+    }
 
     #[test]
     fn test_request_url_builder() {
         assert_eq!("https://accounts.google.\
                     com/o/oauth2/auth?scope=email%20profile&redirect_uri=urn:ietf:wg:oauth:2.0:\
                     oob&response_type=code&client_id=812741506391-h38jh0j4fv0ce1krdkiq0hfvt6n5amr\
-                    f.apps.googleusercontent.com",
+                    f.apps.googleusercontent.com&access_type=offline",
                    build_authentication_request_url("https://accounts.google.com/o/oauth2/auth",
                                                     "812741506391-h38jh0j4fv0ce1krdkiq0hfvt6n5am\
                                                      rf.apps.googleusercontent.com",
                                                     vec![&"email".to_string(),
                                                          &"profile".to_string()],
-                                                    None));
+                                                    None,
+                                                    None,
+                                                    &AuthorizationOptions::default()));
+    }
+
+    #[test]
+    fn test_request_url_options() {
+        let flow = InstalledFlow::new(hyper::Client::new(), None)
+            .with_access_type(Some("online"))
+            .with_prompt("consent select_account")
+            .with_login_hint("a+b@example.com")
+            .with_include_granted_scopes(true);
+        let url = build_authentication_request_url("https://accounts.google.com/o/oauth2/auth",
+                                                   "client-id",
+                                                   &["email"],
+                                                   None,
+                                                   None,
+                                                   &flow.options);
+        assert!(url.ends_with("&client_id=client-id&access_type=online\
+                               &prompt=consent+select_account&login_hint=a%2Bb%40example.com\
+                               &include_granted_scopes=true"));
+
+        let flow = InstalledFlow::new(hyper::Client::new(), None).with_access_type(None);
+        let url = build_authentication_request_url("https://accounts.google.com/o/oauth2/auth",
+                                                   "client-id",
+                                                   &["email"],
+                                                   None,
+                                                   None,
+                                                   &flow.options);
+        assert!(url.ends_with("&client_id=client-id"));
+    }
+
+    #[test]
+    fn test_pkce_challenge() {
+        // The example of RFC 7636, appendix B
+        let pkce = Pkce::from_verifier("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk".to_string());
+        assert_eq!(pkce.challenge(), "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM");
+        assert_eq!(pkce.challenge_method(), "S256");
+
+        let pkce = Pkce::new().unwrap();
+        assert_eq!(pkce.verifier().len(), 43);
+        assert!(pkce.verifier() != Pkce::new().unwrap().verifier());
+        assert_eq!(pkce.challenge(),
+                   Pkce::from_verifier(pkce.verifier().to_string()).challenge());
+    }
+
+    #[test]
+    fn test_pkce_flow() {
+        use refresh::tests::RecordingConnector;
+
+        let appsecret = parse_application_secret(&TEST_APP_SECRET.to_string()).unwrap();
+        let connector = RecordingConnector::new(&["HTTP/1.1 200 OK\r\n\
+                                                   Server: BOGUS\r\n\
+                                                   \r\n\
+                                                   {\"access_token\":\"1/fFAGRNJru1FTz70BzhT3Zg\",\
+                                                   \"expires_in\":3920,\"token_type\":\"Bearer\",\
+                                                   \"refresh_token\":\"1/6BMfW9j53gdGImsixUH6kU5RsR4zwI9lUVX-tqf8JXQ\"}"]);
+        let requests = connector.requests.clone();
+        let pkce = Pkce::from_verifier("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk".to_string());
+        let mut flow = InstalledFlow::new(hyper::Client::with_connector(connector),
+                                          Some(InstalledFlowReturnMethod::HTTPRedirect(0)))
+            .with_pkce(pkce);
+        let mut delegate = RedirectingDelegate {
+            port: flow.port.unwrap(),
+            presented_url: None,
+        };
+
+        let token = flow.obtain_token(&mut delegate, &appsecret, ["email"].iter()).unwrap();
+        assert_eq!(token.access_token, "1/fFAGRNJru1FTz70BzhT3Zg");
+
+        let url = delegate.presented_url.unwrap();
+        assert!(url.contains("&code_challenge=E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM"));
+        assert!(url.contains("&code_challenge_method=S256"));
+        let request = requests.lock().unwrap()[0].clone();
+        assert!(request.contains("code_verifier=dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk"));
     }
 
     #[test]
@@ -353,4 +673,54 @@ mod tests {
         handler.handle_url(url);
         assert_eq!(rx.recv().unwrap(), "ab/c/d".to_string());
     }
+
+    #[test]
+    fn test_redirect_to_free_port() {
+        let appsecret = parse_application_secret(&TEST_APP_SECRET.to_string()).unwrap();
+        let mut flow = InstalledFlow::new(hyper::Client::new(),
+                                          Some(InstalledFlowReturnMethod::HTTPRedirect(0)));
+        let port = flow.port.unwrap();
+        assert!(port != 0);
+
+        let mut delegate = RedirectingDelegate {
+            port: port,
+            presented_url: None,
+        };
+        let code = flow.get_authorization_code(&mut delegate, &appsecret, ["email"].iter())
+            .unwrap();
+        assert_eq!(code, "4/731fJ3BheyCouCniPufAd280GHNV5Ju35yYcGs");
+        assert!(delegate.presented_url
+            .unwrap()
+            .contains(&format!("redirect_uri=http://localhost:{}", port)));
+    }
+
+    #[test]
+    fn test_browser_opener() {
+        let appsecret = parse_application_secret(&TEST_APP_SECRET.to_string()).unwrap();
+        let opener = Arc::new(RecordingOpener::default());
+        let mut flow = InstalledFlow::new(hyper::Client::new(),
+                                          Some(InstalledFlowReturnMethod::HTTPRedirect(0)))
+            .with_browser_opener(opener.clone());
+        let mut delegate = RedirectingDelegate {
+            port: flow.port.unwrap(),
+            presented_url: None,
+        };
+
+        // Failing to open the browser doesn't stop the flow.
+        let code = flow.get_authorization_code(&mut delegate, &appsecret, ["email"].iter())
+            .unwrap();
+        assert_eq!(code, "4/731fJ3BheyCouCniPufAd280GHNV5Ju35yYcGs");
+        assert_eq!(*opener.urls.lock().unwrap(), vec![delegate.presented_url.unwrap()]);
+    }
+
+    #[test]
+    fn test_redirect_timeout() {
+        let appsecret = parse_application_secret(&TEST_APP_SECRET.to_string()).unwrap();
+        let mut flow = InstalledFlow::new(hyper::Client::new(),
+                                          Some(InstalledFlowReturnMethod::HTTPRedirect(0)))
+            .with_redirect_timeout(Duration::from_millis(50));
+
+        assert!(flow.get_authorization_code(&mut IdleDelegate, &appsecret, ["email"].iter())
+            .is_err());
+    }
 }
@@ -0,0 +1,145 @@
+//! A `TokenStorage` keeping tokens in the keyring of the operating system: the Keychain on
+//! macOS, the Credential Manager on Windows, and the Secret Service (e.g. GNOME Keyring) on
+//! Linux.
+//!
+//! Only available with the `keyring` feature.
+
+use std::error::Error;
+use std::fmt;
+
+use keyring::{Keyring, KeyringError};
+use serde_json;
+
+use storage::TokenStorage;
+use types::Token;
+
+/// Stores each token as a password in the OS keyring, under the service `service` and the
+/// account `<account>/<scope hash>`.
+///
+/// Prefer this over `DiskTokenStorage` on desktops, where refresh tokens should not be kept in a
+/// plaintext file.
+///
+/// The keyring can't be searched for the accounts of a service, so `keys()` always returns an
+/// empty list; tokens can only be removed by the scope hash they are stored under.
+pub struct KeyringTokenStorage {
+    service: String,
+    account: String,
+}
+
+impl KeyringTokenStorage {
+    /// `service` usually is the name of the application, and `account` identifies the user.
+    pub fn new(service: &str, account: &str) -> KeyringTokenStorage {
+        KeyringTokenStorage {
+            service: service.to_string(),
+            account: account.to_string(),
+        }
+    }
+
+    fn username(&self, scope_hash: u64) -> String {
+        format!("{}/{}", self.account, scope_hash)
+    }
+}
+
+/// Failures of a `KeyringTokenStorage`.
+#[derive(Debug)]
+pub enum KeyringStorageError {
+    /// No keyring is available on this system, e.g. because the Secret Service isn't running
+    NoBackend,
+    /// The keyring refused to store or hand out a token
+    Keyring(KeyringError),
+    /// A stored token could not be parsed
+    Json(serde_json::Error),
+}
+
+impl From<KeyringError> for KeyringStorageError {
+    fn from(value: KeyringError) -> KeyringStorageError {
+        match value {
+            KeyringError::NoBackendFound => KeyringStorageError::NoBackend,
+            err => KeyringStorageError::Keyring(err),
+        }
+    }
+}
+
+impl From<serde_json::Error> for KeyringStorageError {
+    fn from(value: serde_json::Error) -> KeyringStorageError {
+        KeyringStorageError::Json(value)
+    }
+}
+
+impl fmt::Display for KeyringStorageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            KeyringStorageError::NoBackend => "No keyring is available on this system".fmt(f),
+            KeyringStorageError::Keyring(ref err) => write!(f, "Keyring error: {}", err),
+            KeyringStorageError::Json(ref err) => write!(f, "Invalid stored token: {}", err),
+        }
+    }
+}
+
+impl Error for KeyringStorageError {
+    fn description(&self) -> &str {
+        match *self {
+            KeyringStorageError::NoBackend => "no keyring available",
+            KeyringStorageError::Keyring(_) => "keyring error",
+            KeyringStorageError::Json(_) => "invalid stored token",
+        }
+    }
+}
+
+impl TokenStorage for KeyringTokenStorage {
+    type Error = KeyringStorageError;
+
+    fn set(&mut self,
+           scope_hash: u64,
+           _: &Vec<&str>,
+           token: Option<Token>)
+           -> Result<(), KeyringStorageError> {
+        let username = self.username(scope_hash);
+        let keyring = Keyring::new(&self.service, &username);
+        match token {
+            Some(t) => Ok(try!(keyring.set_password(&try!(serde_json::to_string(&t))))),
+            None => {
+                match keyring.delete_password() {
+                    Ok(()) | Err(KeyringError::NoPasswordFound) => Ok(()),
+                    Err(err) => Err(KeyringStorageError::from(err)),
+                }
+            }
+        }
+    }
+
+    fn get(&self, scope_hash: u64, _: &Vec<&str>) -> Result<Option<Token>, KeyringStorageError> {
+        let username = self.username(scope_hash);
+        match Keyring::new(&self.service, &username).get_password() {
+            Ok(json) => Ok(Some(try!(serde_json::from_str(&json)))),
+            Err(KeyringError::NoPasswordFound) => Ok(None),
+            Err(err) => Err(KeyringStorageError::from(err)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Talks to the real keyring, and passes trivially where there is none.
+    #[test]
+    fn keyring_roundtrip() {
+        let mut storage = KeyringTokenStorage::new("yup-oauth2-test", "keyring_roundtrip");
+        let scopes = vec!["https://www.googleapis.com/auth/drive"];
+        let token = Token::new("1/fFAGRNJru1FTz70BzhT3Zg", "Bearer")
+            .with_refresh_token("1/6BMfW9j53gdGImsixUH6kU5RsR4zwI9lUVX-tqf8JXQ")
+            .with_expiry_timestamp(1500000000);
+
+        match storage.set(42, &scopes, Some(token.clone())) {
+            Err(KeyringStorageError::NoBackend) => return,
+            r => r.unwrap(),
+        }
+        assert_eq!(storage.get(42, &scopes).unwrap(), Some(token));
+        assert_eq!(storage.get(43, &scopes).unwrap(), None);
+
+        storage.set(42, &scopes, None).unwrap();
+        assert_eq!(storage.get(42, &scopes).unwrap(), None);
+        // Removing a token which isn't there is fine.
+        storage.set(42, &scopes, None).unwrap();
+    }
+}
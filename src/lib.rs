@@ -74,32 +74,94 @@ extern crate serde_json;
 extern crate base64;
 extern crate chrono;
 extern crate hyper;
+#[cfg(all(feature = "native-tls", not(feature = "rustls-tls")))]
+extern crate hyper_native_tls;
+#[cfg(feature = "rustls-tls")]
 extern crate hyper_rustls;
+extern crate rand;
+extern crate ring;
 extern crate rustls;
+extern crate untrusted;
 
-#[cfg(test)]
+#[macro_use]
 extern crate log;
 #[cfg(test)]
 extern crate yup_hyper_mock;
 extern crate url;
 extern crate itertools;
+#[cfg(feature = "keyring")]
+extern crate keyring;
+#[cfg(feature = "open")]
+extern crate open;
+#[cfg(feature = "qrcode")]
+extern crate qrcode;
+#[cfg(feature = "redis")]
+extern crate redis;
+#[cfg(feature = "rusqlite")]
+extern crate rusqlite;
+#[cfg(feature = "secrecy")]
+extern crate secrecy;
 
 mod authenticator;
 mod authenticator_delegate;
+mod browser;
+mod cli;
+mod client_credentials;
+mod clock;
 mod device;
+mod error;
 mod helper;
+mod id_token;
 mod installed;
+mod metrics;
+#[cfg(feature = "keyring")]
+mod keyring_storage;
+mod provider;
+#[cfg(feature = "redis")]
+mod redis_storage;
 mod refresh;
+#[cfg(feature = "secrecy")]
+mod secret_token;
 mod service_account;
+#[cfg(feature = "rusqlite")]
+mod sqlite_storage;
 mod storage;
+mod token_exchange;
 mod types;
 
-pub use device::{GOOGLE_DEVICE_CODE_URL, DeviceFlow};
-pub use refresh::{RefreshFlow, RefreshResult};
-pub use types::{Token, FlowType, ApplicationSecret, ConsoleApplicationSecret, Scheme, TokenType};
-pub use installed::{InstalledFlow, InstalledFlowReturnMethod};
-pub use storage::{TokenStorage, NullStorage, MemoryStorage, DiskTokenStorage};
-pub use authenticator::{Authenticator, Retry, GetToken};
+pub use browser::{BrowserOpener, NoBrowser};
+#[cfg(feature = "open")]
+pub use browser::DesktopBrowser;
+pub use cli::{print_device_instructions, write_device_instructions};
+#[cfg(feature = "open")]
+pub use cli::open_verification_url;
+pub use client_credentials::ClientCredentialsFlow;
+pub use clock::{Clock, SystemClock, MockClock};
+pub use device::{GOOGLE_DEVICE_CODE_URL, DeviceFlow, PollStatus};
+pub use error::Error;
+pub use id_token::{IdTokenClaims, IdTokenVerifier, GOOGLE_JWKS_URL};
+pub use refresh::{RefreshFlow, RefreshFlowBuilder, RefreshResult};
+pub use types::{Token, FlowType, ApplicationSecret, ApplicationSecretBuilder,
+                ConsoleApplicationSecret, ClientAuthMethod, Scheme, TokenType, Introspection,
+                DEFAULT_USER_AGENT};
+pub use installed::{InstalledFlow, InstalledFlowReturnMethod, Pkce};
+pub use metrics::{Metrics, NoMetrics};
+pub use provider::Provider;
+pub use token_exchange::{ExchangedToken, TokenExchangeFlow, ACCESS_TOKEN_TYPE, ID_TOKEN_TYPE,
+                         JWT_TOKEN_TYPE, REFRESH_TOKEN_TYPE};
+pub use storage::{TokenStorage, NullStorage, MemoryStorage, DiskTokenStorage,
+                  EncryptedDiskTokenStorage, hash_scopes, hash_scopes_for_account,
+                  migrate_to_account};
+#[cfg(feature = "keyring")]
+pub use keyring_storage::{KeyringTokenStorage, KeyringStorageError};
+#[cfg(feature = "redis")]
+pub use redis_storage::{RedisTokenStorage, RedisStorageError};
+#[cfg(feature = "secrecy")]
+pub use secret_token::SecretToken;
+#[cfg(feature = "rusqlite")]
+pub use sqlite_storage::{SqliteTokenStorage, SqliteStorageError};
+pub use authenticator::{Authenticator, AuthorizedRequestBuilder, Retry, GetToken,
+                        SharedAuthenticator, DEFAULT_CLOCK_SKEW_SECS, GOOGLE_REVOKE_URL};
 pub use authenticator_delegate::{AuthenticatorDelegate, DefaultAuthenticatorDelegate, PollError,
                                  PollInformation};
 pub use helper::*;
@@ -0,0 +1,144 @@
+//! Hooks to observe token refreshes, e.g. to export counts and latencies to Prometheus or statsd.
+
+use std::time::Duration;
+
+use error::Error;
+
+/// Receives events of `RefreshFlow`s and the `Authenticator`s using them. All methods do nothing
+/// by default, so that adapters only implement those they are interested in.
+///
+/// A refresh, including all its retries, is reported once: `on_refresh_start()` is followed by
+/// exactly one of `on_refresh_success()` and `on_refresh_failure()`.
+///
+/// # Examples
+/// ```
+/// # extern crate hyper;
+/// # extern crate yup_oauth2 as oauth2;
+/// use oauth2::{Metrics, RefreshFlow};
+/// use std::sync::Arc;
+/// use std::sync::atomic::{AtomicUsize, Ordering};
+/// use std::time::Duration;
+///
+/// #[derive(Default)]
+/// struct RefreshCounter(AtomicUsize);
+///
+/// impl Metrics for RefreshCounter {
+///     fn on_refresh_success(&self, _: Duration) {
+///         self.0.fetch_add(1, Ordering::SeqCst);
+///     }
+/// }
+///
+/// # fn main() {
+/// let counter = Arc::new(RefreshCounter::default());
+/// let flow = RefreshFlow::new(hyper::Client::new()).with_metrics(counter.clone());
+/// # }
+/// ```
+pub trait Metrics: Send + Sync {
+    /// Called before the first request of a refresh.
+    fn on_refresh_start(&self) {}
+
+    /// Called when a refresh obtained a new token, `latency` after it started.
+    fn on_refresh_success(&self, _latency: Duration) {}
+
+    /// Called when a refresh gave up, with the reason of its last attempt.
+    fn on_refresh_failure(&self, _error: &Error) {}
+}
+
+/// Ignores all events; used unless other `Metrics` are set.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct NoMetrics;
+
+impl Metrics for NoMetrics {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use authenticator::Authenticator;
+    use authenticator_delegate::DefaultAuthenticatorDelegate;
+    use helper::parse_application_secret;
+    use hyper;
+    use refresh::RefreshFlow;
+    use refresh::tests::{RecordingConnector, TOKEN_REPLY};
+    use serde_json as json;
+    use storage::{hash_scopes, MemoryStorage, TokenStorage};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use types::{ConsoleApplicationSecret, FlowType, Token};
+    use types::tests::SECRET;
+
+    #[derive(Default)]
+    struct CountingMetrics {
+        starts: Mutex<usize>,
+        successes: Mutex<Vec<Duration>>,
+        failures: Mutex<Vec<String>>,
+    }
+
+    impl Metrics for CountingMetrics {
+        fn on_refresh_start(&self) {
+            *self.starts.lock().unwrap() += 1;
+        }
+
+        fn on_refresh_success(&self, latency: Duration) {
+            self.successes.lock().unwrap().push(latency);
+        }
+
+        fn on_refresh_failure(&self, error: &Error) {
+            self.failures.lock().unwrap().push(error.to_string());
+        }
+    }
+
+    #[test]
+    fn refresh_flow_reports_metrics() {
+        let secret = json::from_str::<ConsoleApplicationSecret>(SECRET).unwrap().installed.unwrap();
+        let metrics = Arc::new(CountingMetrics::default());
+        let connector = RecordingConnector::new(&["HTTP/1.1 503 Service Unavailable\r\n\r\n",
+                                                  TOKEN_REPLY,
+                                                  "HTTP/1.1 400 Bad Request\r\n\
+                                                   \r\n\
+                                                   {\"error\":\"invalid_grant\"}"]);
+        let mut client = hyper::Client::with_connector(connector);
+
+        // One refresh, although it took two attempts.
+        assert!(RefreshFlow::new(&mut client)
+            .with_retry(2, Duration::from_millis(1))
+            .with_metrics(metrics.clone())
+            .obtain_refreshed(&secret, "bogus_refresh_token")
+            .is_ok());
+        assert_eq!(*metrics.starts.lock().unwrap(), 1);
+        assert_eq!(metrics.successes.lock().unwrap().len(), 1);
+        assert!(metrics.failures.lock().unwrap().is_empty());
+
+        assert!(RefreshFlow::new(&mut client)
+            .with_metrics(metrics.clone())
+            .obtain_refreshed(&secret, "bogus_refresh_token")
+            .is_err());
+        assert_eq!(*metrics.starts.lock().unwrap(), 2);
+        assert_eq!(*metrics.failures.lock().unwrap(), vec!["invalid_grant".to_string()]);
+    }
+
+    #[test]
+    fn authenticator_reports_metrics() {
+        let secret = parse_application_secret(&SECRET.to_string()).unwrap();
+        let scopes = ["https://www.googleapis.com/auth/youtube.upload"];
+        let (hash, sorted_scopes) = hash_scopes(&scopes);
+        let mut storage = MemoryStorage::default();
+        storage.set(hash,
+                 &sorted_scopes,
+                 Some(Token::new("stored_access_token", "Bearer")
+                     .with_refresh_token("1/6BMfW9j53gdGImsixUH6kU5RsR4zwI9lUVX-tqf8JXQ")
+                     .with_expires_in(-10)))
+            .unwrap();
+        let metrics = Arc::new(CountingMetrics::default());
+        let client = hyper::Client::with_connector(RecordingConnector::new(&[TOKEN_REPLY]));
+        let mut auth = Authenticator::new(&secret,
+                                          DefaultAuthenticatorDelegate,
+                                          client,
+                                          storage,
+                                          Some(FlowType::InstalledInteractive))
+            .with_metrics(metrics.clone());
+
+        assert_eq!(auth.force_token(&scopes).unwrap().access_token, "1/fFAGRNJru1FTz70BzhT3Zg");
+        assert_eq!(*metrics.starts.lock().unwrap(), 1);
+        assert_eq!(metrics.successes.lock().unwrap().len(), 1);
+    }
+}
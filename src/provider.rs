@@ -0,0 +1,127 @@
+//! Endpoints of OAuth 2.0 providers.
+//!
+//! The flows of this crate talk to whichever endpoints they are given, so they work with any
+//! provider implementing the standard grants. `Provider` bundles those endpoints, and comes
+//! with presets for some well-known identity providers.
+
+use authenticator::GOOGLE_REVOKE_URL;
+use device::GOOGLE_DEVICE_CODE_URL;
+use types::ApplicationSecret;
+
+/// The endpoints of an OAuth 2.0 identity provider.
+///
+/// Use `Authenticator::with_provider()` to have an authenticator talk to them, or
+/// `application_secret()` to build a secret for use with the individual flows.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Provider {
+    /// The authorization endpoint, to which users are sent to grant access
+    pub auth_uri: String,
+    /// The token endpoint, at which codes and refresh tokens are exchanged for access tokens
+    pub token_uri: String,
+    /// The [device authorization endpoint](https://tools.ietf.org/html/rfc8628), if supported
+    pub device_code_uri: Option<String>,
+    /// The [revocation endpoint](https://tools.ietf.org/html/rfc7009), if supported
+    pub revocation_uri: Option<String>,
+}
+
+impl Provider {
+    /// Google's endpoints. This is what the crate uses unless told otherwise.
+    pub fn google() -> Provider {
+        Provider {
+            auth_uri: "https://accounts.google.com/o/oauth2/auth".to_string(),
+            token_uri: "https://accounts.google.com/o/oauth2/token".to_string(),
+            device_code_uri: Some(GOOGLE_DEVICE_CODE_URL.to_string()),
+            revocation_uri: Some(GOOGLE_REVOKE_URL.to_string()),
+        }
+    }
+
+    /// GitHub's endpoints. GitHub does not support token revocation through OAuth.
+    pub fn github() -> Provider {
+        Provider {
+            auth_uri: "https://github.com/login/oauth/authorize".to_string(),
+            token_uri: "https://github.com/login/oauth/access_token".to_string(),
+            device_code_uri: Some("https://github.com/login/device/code".to_string()),
+            revocation_uri: None,
+        }
+    }
+
+    /// The endpoints of the Microsoft identity platform, for both work and personal accounts
+    /// (the `common` tenant). Microsoft does not support token revocation through OAuth.
+    pub fn microsoft() -> Provider {
+        let base = "https://login.microsoftonline.com/common/oauth2/v2.0";
+        Provider {
+            auth_uri: format!("{}/authorize", base),
+            token_uri: format!("{}/token", base),
+            device_code_uri: Some(format!("{}/devicecode", base)),
+            revocation_uri: None,
+        }
+    }
+
+    /// Returns a secret for the client registered with this provider as `client_id`.
+    pub fn application_secret(&self, client_id: &str, client_secret: &str) -> ApplicationSecret {
+        ApplicationSecret {
+            client_id: client_id.to_string(),
+            client_secret: client_secret.to_string(),
+            token_uri: self.token_uri.clone(),
+            auth_uri: self.auth_uri.clone(),
+            redirect_uris: Vec::new(),
+            project_id: None,
+            client_email: None,
+            auth_provider_x509_cert_url: None,
+            client_x509_cert_url: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper;
+    use refresh::{RefreshFlow, RefreshResult};
+    use refresh::tests::{RecordingConnector, TOKEN_REPLY};
+    use types::FlowType;
+
+    #[test]
+    fn presets() {
+        let google = Provider::google();
+        assert_eq!(google.device_code_uri, Some(GOOGLE_DEVICE_CODE_URL.to_string()));
+        assert_eq!(google.revocation_uri, Some(GOOGLE_REVOKE_URL.to_string()));
+        assert_eq!(Provider::github().token_uri,
+                   "https://github.com/login/oauth/access_token");
+        assert_eq!(Provider::microsoft().device_code_uri,
+                   Some("https://login.microsoftonline.com/common/oauth2/v2.0/devicecode"
+                       .to_string()));
+    }
+
+    #[test]
+    fn refresh_with_custom_provider() {
+        let provider = Provider {
+            auth_uri: "https://idp.example.com/oauth/authorize".to_string(),
+            token_uri: "https://idp.example.com/oauth/token".to_string(),
+            device_code_uri: Some("https://idp.example.com/oauth/device".to_string()),
+            revocation_uri: None,
+        };
+        let secret = provider.application_secret("client-id", "client-secret");
+        assert_eq!(secret.token_uri, provider.token_uri);
+
+        let connector = RecordingConnector::new(&[TOKEN_REPLY]);
+        let requests = connector.requests.clone();
+        let mut c = hyper::Client::with_connector(connector);
+        let mut flow = RefreshFlow::new(&mut c);
+        match *flow.refresh_token(FlowType::Device(provider.device_code_uri.clone().unwrap()),
+                                  &secret,
+                                  "refresh-token") {
+            RefreshResult::Success(ref t) => {
+                assert_eq!(t.access_token, "1/fFAGRNJru1FTz70BzhT3Zg");
+            }
+            ref r => panic!("Expected a Success, got {:?}", r),
+        }
+
+        let request = requests.lock().unwrap()[0].clone();
+        assert!(request.starts_with("POST /oauth/token HTTP/1.1\r\n"));
+        assert!(request.contains("Host: idp.example.com\r\n"));
+        assert!(request.contains("Accept: application/json\r\n"));
+        assert!(request.contains("client_id=client-id"));
+        assert!(request.contains("refresh_token=refresh-token"));
+    }
+}
@@ -0,0 +1,338 @@
+//! A `TokenStorage` keeping tokens in Redis, to share them between the instances of a
+//! horizontally scaled service.
+//!
+//! Only available with the `redis` feature.
+
+use std::error::Error;
+use std::fmt;
+
+use chrono::UTC;
+use redis::{self, ConnectionLike, RedisError};
+use serde_json;
+
+use storage::TokenStorage;
+use types::Token;
+
+/// Stores each token as JSON under the key `<namespace>:<scope hash>`.
+///
+/// Tokens with a refresh token are kept after they expire, so that the `Authenticator` can
+/// refresh them; only tokens without one, which are of no use once expired, are expired by
+/// Redis along with the token.
+///
+/// # Examples
+/// ```no_run
+/// # extern crate redis;
+/// # extern crate yup_oauth2 as oauth2;
+/// # fn main() {
+/// let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+/// let storage = oauth2::RedisTokenStorage::new(client.get_connection().unwrap(), "my-service");
+/// # }
+/// ```
+pub struct RedisTokenStorage<C = redis::Connection> {
+    conn: C,
+    namespace: String,
+}
+
+impl<C> RedisTokenStorage<C>
+    where C: ConnectionLike
+{
+    /// Keeps tokens in the database `conn` is connected to. `namespace` separates them from the
+    /// tokens of other applications using the same database.
+    pub fn new(conn: C, namespace: &str) -> RedisTokenStorage<C> {
+        RedisTokenStorage {
+            conn: conn,
+            namespace: namespace.to_string(),
+        }
+    }
+
+    fn key(&self, scope_hash: u64) -> String {
+        format!("{}:{}", self.namespace, scope_hash)
+    }
+
+    // The `SCAN` pattern matching the keys of all tokens, with the namespace taken literally.
+    fn key_pattern(&self) -> String {
+        let mut pattern = String::new();
+        for c in self.namespace.chars() {
+            if "*?[]\\".contains(c) {
+                pattern.push('\\');
+            }
+            pattern.push(c);
+        }
+        pattern.push_str(":*");
+        pattern
+    }
+}
+
+/// Failures of a `RedisTokenStorage`.
+#[derive(Debug)]
+pub enum RedisStorageError {
+    /// Redis could not be reached, or refused a command
+    Redis(RedisError),
+    /// A stored token could not be parsed
+    Json(serde_json::Error),
+}
+
+impl From<RedisError> for RedisStorageError {
+    fn from(value: RedisError) -> RedisStorageError {
+        RedisStorageError::Redis(value)
+    }
+}
+
+impl From<serde_json::Error> for RedisStorageError {
+    fn from(value: serde_json::Error) -> RedisStorageError {
+        RedisStorageError::Json(value)
+    }
+}
+
+impl fmt::Display for RedisStorageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            RedisStorageError::Redis(ref err) => write!(f, "Redis error: {}", err),
+            RedisStorageError::Json(ref err) => write!(f, "Invalid stored token: {}", err),
+        }
+    }
+}
+
+impl Error for RedisStorageError {
+    fn description(&self) -> &str {
+        match *self {
+            RedisStorageError::Redis(_) => "redis error",
+            RedisStorageError::Json(_) => "invalid stored token",
+        }
+    }
+}
+
+impl<C> TokenStorage for RedisTokenStorage<C>
+    where C: ConnectionLike
+{
+    type Error = RedisStorageError;
+
+    fn set(&mut self,
+           scope_hash: u64,
+           _: &Vec<&str>,
+           token: Option<Token>)
+           -> Result<(), RedisStorageError> {
+        let key = self.key(scope_hash);
+        let token = match token {
+            Some(t) => t,
+            None => return Ok(try!(redis::cmd("DEL").arg(&key).query(&self.conn))),
+        };
+        let json = try!(serde_json::to_string(&token));
+        if !token.refresh_token.is_empty() {
+            return Ok(try!(redis::cmd("SET").arg(&key).arg(json).query(&self.conn)));
+        }
+        match token.expires_in_timestamp.map(|t| t - UTC::now().timestamp()) {
+            // A token which has already expired would be dropped by Redis right away.
+            Some(ttl) if ttl <= 0 => Ok(try!(redis::cmd("DEL").arg(&key).query(&self.conn))),
+            Some(ttl) => {
+                Ok(try!(redis::cmd("SET")
+                    .arg(&key)
+                    .arg(json)
+                    .arg("EX")
+                    .arg(ttl)
+                    .query(&self.conn)))
+            }
+            None => Ok(try!(redis::cmd("SET").arg(&key).arg(json).query(&self.conn))),
+        }
+    }
+
+    fn get(&self, scope_hash: u64, _: &Vec<&str>) -> Result<Option<Token>, RedisStorageError> {
+        let json: Option<String> =
+            try!(redis::cmd("GET").arg(self.key(scope_hash)).query(&self.conn));
+        let token: Token = match json {
+            Some(json) => try!(serde_json::from_str(&json)),
+            None => return Ok(None),
+        };
+        // Redis expires keys lazily, to the second; don't hand out a token it still has.
+        match token.expires_in_timestamp {
+            Some(t) if t <= UTC::now().timestamp() && token.refresh_token.is_empty() => Ok(None),
+            _ => Ok(Some(token)),
+        }
+    }
+
+    fn keys(&self) -> Result<Vec<u64>, RedisStorageError> {
+        let pattern = self.key_pattern();
+        let prefix = format!("{}:", self.namespace);
+        let mut keys = Vec::new();
+        let mut cursor = 0u64;
+        loop {
+            let (next, batch): (u64, Vec<String>) = try!(redis::cmd("SCAN")
+                .arg(cursor)
+                .arg("MATCH")
+                .arg(&pattern)
+                .query(&self.conn));
+            // Other keys of the namespace, which aren't tokens, are skipped.
+            keys.extend(batch.iter()
+                .filter_map(|key| key[prefix.len()..].parse::<u64>().ok()));
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+        // `SCAN` may return a key more than once.
+        keys.sort();
+        keys.dedup();
+        Ok(keys)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use redis::{RedisResult, Value};
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::env;
+
+    /// Understands just enough of the Redis protocol to run `SET`, `GET`, `DEL` and `SCAN`,
+    /// which returns all keys matching a `<prefix>*` pattern at once.
+    #[derive(Default)]
+    struct MockConnection {
+        data: RefCell<HashMap<String, String>>,
+        commands: RefCell<Vec<Vec<String>>>,
+    }
+
+    impl ConnectionLike for MockConnection {
+        fn req_packed_command(&self, cmd: &[u8]) -> RedisResult<Value> {
+            // Commands arrive as arrays of bulk strings: `*<n>`, then `$<len>` and the
+            // argument for each of them.
+            let cmd = String::from_utf8_lossy(cmd).into_owned();
+            let args = cmd.split("\r\n")
+                .filter(|l| !l.is_empty() && !l.starts_with('*') && !l.starts_with('$'))
+                .map(|l| l.to_string())
+                .collect::<Vec<String>>();
+            self.commands.borrow_mut().push(args.clone());
+            let mut data = self.data.borrow_mut();
+            Ok(match args[0].as_str() {
+                "SET" => {
+                    data.insert(args[1].clone(), args[2].clone());
+                    Value::Okay
+                }
+                "GET" => {
+                    match data.get(&args[1]) {
+                        Some(v) => Value::Data(v.clone().into_bytes()),
+                        None => Value::Nil,
+                    }
+                }
+                "DEL" => Value::Int(data.remove(&args[1]).map_or(0, |_| 1)),
+                "SCAN" => {
+                    let prefix = &args[3][..args[3].len() - 1];
+                    let keys = data.keys()
+                        .filter(|k| k.starts_with(prefix))
+                        .map(|k| Value::Data(k.clone().into_bytes()))
+                        .collect();
+                    Value::Bulk(vec![Value::Data(b"0".to_vec()), Value::Bulk(keys)])
+                }
+                other => panic!("Unexpected command {}", other),
+            })
+        }
+
+        fn req_packed_commands(&self,
+                               _: &[u8],
+                               _: usize,
+                               _: usize)
+                               -> RedisResult<Vec<Value>> {
+            // The storage doesn't use pipelines.
+            Ok(Vec::new())
+        }
+
+        fn get_db(&self) -> i64 {
+            0
+        }
+    }
+
+    fn test_token(expires_in: i64) -> Token {
+        Token::new("1/fFAGRNJru1FTz70BzhT3Zg", "Bearer")
+            .with_refresh_token("1/6BMfW9j53gdGImsixUH6kU5RsR4zwI9lUVX-tqf8JXQ")
+            .with_expires_in(expires_in)
+    }
+
+    fn unrefreshable_token(expires_in: i64) -> Token {
+        Token::new("1/fFAGRNJru1FTz70BzhT3Zg", "Bearer").with_expires_in(expires_in)
+    }
+
+    #[test]
+    fn redis_storage_keeps_refreshable_tokens() {
+        let mut storage = RedisTokenStorage::new(MockConnection::default(), "test");
+        let scopes = vec!["https://www.googleapis.com/auth/drive"];
+        let token = test_token(-10);
+
+        storage.set(42, &scopes, Some(token.clone())).unwrap();
+        assert_eq!(storage.conn.commands.borrow()[0].len(), 3);
+        // Expired, but the refresh token is still good for a new one.
+        assert_eq!(storage.get(42, &scopes).unwrap(), Some(token));
+    }
+
+    #[test]
+    fn redis_storage_sets_ttl() {
+        let mut storage = RedisTokenStorage::new(MockConnection::default(), "test");
+        let scopes = vec!["https://www.googleapis.com/auth/drive"];
+        let token = unrefreshable_token(3600);
+
+        storage.set(42, &scopes, Some(token.clone())).unwrap();
+        {
+            let commands = storage.conn.commands.borrow();
+            assert_eq!(commands[0][0], "SET");
+            assert_eq!(commands[0][1], "test:42");
+            assert_eq!(commands[0][3], "EX");
+            let ttl: i64 = commands[0][4].parse().unwrap();
+            assert!(ttl > 3500 && ttl <= 3600);
+        }
+        assert_eq!(storage.get(42, &scopes).unwrap(), Some(token));
+        assert_eq!(storage.get(43, &scopes).unwrap(), None);
+
+        storage.set(42, &scopes, None).unwrap();
+        assert_eq!(storage.get(42, &scopes).unwrap(), None);
+    }
+
+    #[test]
+    fn redis_storage_drops_expired_tokens() {
+        let mut storage = RedisTokenStorage::new(MockConnection::default(), "test");
+        let scopes = vec!["https://www.googleapis.com/auth/drive"];
+
+        storage.set(42, &scopes, Some(unrefreshable_token(-10))).unwrap();
+        assert_eq!(storage.conn.commands.borrow()[0][0], "DEL");
+        assert_eq!(storage.get(42, &scopes).unwrap(), None);
+
+        // Not yet expired by Redis, but no longer valid.
+        let json = serde_json::to_string(&unrefreshable_token(-10)).unwrap();
+        storage.conn.data.borrow_mut().insert("test:42".to_string(), json);
+        assert_eq!(storage.get(42, &scopes).unwrap(), None);
+    }
+
+    #[test]
+    fn redis_storage_lists_keys() {
+        let mut storage = RedisTokenStorage::new(MockConnection::default(), "test");
+        let scopes = vec!["https://www.googleapis.com/auth/drive"];
+
+        storage.set(42, &scopes, Some(test_token(3600))).unwrap();
+        storage.set(u64::max_value(), &scopes, Some(test_token(3600))).unwrap();
+        storage.set(7, &scopes, Some(test_token(3600))).unwrap();
+        storage.conn.data.borrow_mut().insert("test:lock".to_string(), String::new());
+        storage.conn.data.borrow_mut().insert("other:5".to_string(), String::new());
+        assert_eq!(storage.keys().unwrap(), vec![7, 42, u64::max_value()]);
+        assert_eq!(storage.conn.commands.borrow()[3], ["SCAN", "0", "MATCH", "test:*"]);
+    }
+
+    // Talks to the server at `REDIS_URL`, and passes trivially if that isn't set.
+    #[test]
+    fn redis_storage_roundtrip() {
+        let url = match env::var("REDIS_URL") {
+            Ok(url) => url,
+            Err(_) => return,
+        };
+        let conn = redis::Client::open(url.as_str()).unwrap().get_connection().unwrap();
+        let mut storage = RedisTokenStorage::new(conn, "yup-oauth2-test");
+        let scopes = vec!["https://www.googleapis.com/auth/drive"];
+        let token = unrefreshable_token(60);
+
+        storage.set(42, &scopes, Some(token.clone())).unwrap();
+        assert_eq!(storage.get(42, &scopes).unwrap(), Some(token));
+        let ttl: i64 =
+            redis::cmd("TTL").arg("yup-oauth2-test:42").query(&storage.conn).unwrap();
+        assert!(ttl > 0 && ttl <= 60);
+
+        storage.set(42, &scopes, None).unwrap();
+        assert_eq!(storage.get(42, &scopes).unwrap(), None);
+    }
+}
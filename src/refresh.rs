@@ -1,13 +1,25 @@
-use types::{ApplicationSecret, FlowType, JsonError};
+use types::{ensure_https, ApplicationSecret, ClientAuthMethod, FlowType, JsonError,
+            DEFAULT_USER_AGENT};
 
-use chrono::UTC;
+use clock::{Clock, SystemClock};
+use error;
+use metrics::{Metrics, NoMetrics};
+
+use chrono::{self, DateTime, UTC};
 use hyper;
-use hyper::header::ContentType;
+use hyper::header::{Accept, Authorization, Basic, ContentType, Headers, UserAgent};
+use hyper::status::StatusCode;
+use itertools::Itertools;
+use rand::{self, Rng};
 use serde_json as json;
 use url::form_urlencoded;
 use super::Token;
 use std::borrow::BorrowMut;
-use std::io::Read;
+use std::cmp::{max, min};
+use std::io::{self, Read};
+use std::sync::Arc;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
 
 /// Implements the [Outh2 Refresh Token Flow](https://developers.google.com/youtube/v3/guides/authentication#devices).
 ///
@@ -16,28 +28,253 @@ use std::io::Read;
 /// and valid access token.
 pub struct RefreshFlow<C> {
     client: C,
-    result: RefreshResult,
+    result: Option<RefreshResult>,
+    timeout: Option<Duration>,
+    max_attempts: u32,
+    base_delay: Duration,
+    client_auth: ClientAuthMethod,
+    token_uri: Option<String>,
+    insecure_http: bool,
+    user_agent: String,
+    headers: Headers,
+    extra_params: Vec<(String, String)>,
+    scope: Option<String>,
+    on_refresh: Option<Box<Fn(&Token)>>,
+    clock: Arc<Clock>,
+    metrics: Arc<Metrics>,
 }
 
 
 /// All possible outcomes of the refresh flow
+#[derive(Debug)]
 pub enum RefreshResult {
     /// Indicates connection failure
     Error(hyper::Error),
     /// The server did not answer with a new token, providing the server message
     RefreshError(String, Option<String>),
+    /// The server failed to handle the request (with a 5xx status) and may be asked again
+    /// later
+    ServerError(StatusCode),
+    /// The server is rate limiting us (HTTP status 429). Contains the time it asked us to wait
+    /// with its `Retry-After` header, or zero if it didn't say
+    RateLimited(Duration),
+    /// The server didn't answer within the duration set by `RefreshFlow::with_timeout()`
+    Timeout,
+    /// The server's answer could not be understood. Contains the reason, followed by the
+    /// (possibly truncated) body the server sent
+    InvalidResponse(String),
+    /// The token endpoint doesn't use HTTPS, so no request was made; see
+    /// `RefreshFlow::with_insecure_http()`
+    InsecureUri(String),
     /// The refresh operation finished successfully, providing a new `Token`
     Success(Token),
 }
 
+impl RefreshResult {
+    fn from_http_error(err: hyper::Error) -> RefreshResult {
+        match err {
+            // A socket read timeout surfaces as `WouldBlock` on unix systems.
+            hyper::Error::Io(ref io_err) if io_err.kind() == io::ErrorKind::TimedOut ||
+                                             io_err.kind() == io::ErrorKind::WouldBlock => {
+                RefreshResult::Timeout
+            }
+            err => RefreshResult::Error(err),
+        }
+    }
+
+    /// Returns the token of a `Success`, or else the reason why there is none as an
+    /// `error::Error`, so that `?` and `try!` can be used.
+    pub fn into_result(self) -> Result<Token, error::Error> {
+        match self {
+            RefreshResult::Success(token) => Ok(token),
+            RefreshResult::Error(err) => Err(error::Error::HttpError(err)),
+            RefreshResult::RefreshError(error, desc) => Err(error::Error::AuthError(error, desc)),
+            RefreshResult::ServerError(status) => Err(error::Error::BadStatus(status)),
+            RefreshResult::RateLimited(delay) => Err(error::Error::RateLimited(delay)),
+            RefreshResult::Timeout => {
+                Err(error::Error::IoError(io::Error::new(io::ErrorKind::TimedOut,
+                                                         "Token refresh timed out")))
+            }
+            RefreshResult::InvalidResponse(reason) => {
+                Err(error::Error::InvalidResponse(reason))
+            }
+            RefreshResult::InsecureUri(uri) => Err(error::Error::InsecureUri(uri)),
+        }
+    }
+
+    // Like `into_result()`, for reporting a failure while keeping it; `None` for a `Success`.
+    // Connection errors can't be copied, and become an `Io` error with the same message instead.
+    fn to_error(&self) -> Option<error::Error> {
+        let err = match *self {
+            RefreshResult::Error(ref err) => {
+                let kind = match *err {
+                    hyper::Error::Io(ref io_err) => io_err.kind(),
+                    _ => io::ErrorKind::Other,
+                };
+                error::Error::HttpError(hyper::Error::Io(io::Error::new(kind, err.to_string())))
+            }
+            RefreshResult::RefreshError(ref error, ref desc) => {
+                error::Error::AuthError(error.clone(), desc.clone())
+            }
+            RefreshResult::ServerError(status) => error::Error::BadStatus(status),
+            RefreshResult::RateLimited(delay) => error::Error::RateLimited(delay),
+            RefreshResult::Timeout => {
+                error::Error::IoError(io::Error::new(io::ErrorKind::TimedOut,
+                                                     "Token refresh timed out"))
+            }
+            RefreshResult::InvalidResponse(ref reason) => {
+                error::Error::InvalidResponse(reason.clone())
+            }
+            RefreshResult::InsecureUri(ref uri) => error::Error::InsecureUri(uri.clone()),
+            RefreshResult::Success(_) => return None,
+        };
+        Some(err)
+    }
+
+    /// Whether the server refused the refresh token with `invalid_grant`, after which the user
+    /// has to authorize again; see `Error::needs_reauth()`.
+    pub fn needs_reauth(&self) -> bool {
+        match *self {
+            RefreshResult::RefreshError(ref error, _) => error == error::INVALID_GRANT,
+            _ => false,
+        }
+    }
+
+    /// Whether the refresh failed for a reason that may go away when trying again.
+    fn is_transient(&self) -> bool {
+        match *self {
+            RefreshResult::Error(_) |
+            RefreshResult::Timeout |
+            RefreshResult::RateLimited(_) |
+            RefreshResult::ServerError(_) => true,
+            RefreshResult::RefreshError(_, _) |
+            RefreshResult::InvalidResponse(_) |
+            RefreshResult::InsecureUri(_) |
+            RefreshResult::Success(_) => false,
+        }
+    }
+}
+
+impl From<RefreshResult> for Result<Token, error::Error> {
+    fn from(value: RefreshResult) -> Result<Token, error::Error> {
+        value.into_result()
+    }
+}
+
 impl<C> RefreshFlow<C>
     where C: BorrowMut<hyper::Client>
 {
+    /// Returns a flow with default options; use `RefreshFlowBuilder` or the `with_*()` methods
+    /// to change them.
     pub fn new(client: C) -> RefreshFlow<C> {
-        RefreshFlow {
-            client: client,
-            result: RefreshResult::Error(hyper::Error::TooLarge),
-        }
+        RefreshFlowBuilder::new().build(client)
+    }
+
+    /// Limit the time to wait for the token server while sending the request or reading its
+    /// reply. By default, there is no timeout (unless the client passed to `new()` was set up
+    /// with one), and `refresh_token()` blocks until the server answers.
+    ///
+    /// Note that the timeout is applied to the client used by this flow.
+    pub fn with_timeout(mut self, timeout: Duration) -> RefreshFlow<C> {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Retry failed refresh attempts up to a total of `max_attempts` requests, waiting
+    /// exponentially longer between them, starting at `base_delay` and growing to at most five
+    /// minutes (plus jitter). Only connection problems,
+    /// timeouts, rate limiting and 5xx server errors are retried; a refusal by the server (like
+    /// `invalid_grant`) is final. The `RefreshResult` reflects the last attempt.
+    ///
+    /// When rate limited, the flow waits as long as the server's `Retry-After` header asks,
+    /// instead of its own delay. If that is longer than five minutes, the flow gives up instead,
+    /// with the `RateLimited` result, so that the caller decides whether to wait that long.
+    ///
+    /// By default, only a single request is made.
+    pub fn with_retry(mut self, max_attempts: u32, base_delay: Duration) -> RefreshFlow<C> {
+        self.max_attempts = max(max_attempts, 1);
+        self.base_delay = base_delay;
+        self
+    }
+
+    /// Choose how to present the client credentials to the token endpoint. By default,
+    /// they are sent in the request body.
+    pub fn with_client_auth(mut self, method: ClientAuthMethod) -> RefreshFlow<C> {
+        self.client_auth = method;
+        self
+    }
+
+    /// Send refresh requests to `token_uri` instead of the `token_uri` of the secret passed to
+    /// `refresh_token()`. Useful for providers other than Google, or for test servers.
+    pub fn with_token_uri(mut self, token_uri: String) -> RefreshFlow<C> {
+        self.token_uri = Some(token_uri);
+        self
+    }
+
+    /// Allow sending the client secret and refresh token to a token endpoint which doesn't use
+    /// HTTPS, e.g. a local test server. By default, such a refresh fails with
+    /// `RefreshResult::InsecureUri`, without contacting the server.
+    pub fn with_insecure_http(mut self, allow: bool) -> RefreshFlow<C> {
+        self.insecure_http = allow;
+        self
+    }
+
+    /// Send `user_agent` as `User-Agent` header, instead of `DEFAULT_USER_AGENT`.
+    pub fn with_user_agent(mut self, user_agent: String) -> RefreshFlow<C> {
+        self.user_agent = user_agent;
+        self
+    }
+
+    /// Send `headers` along with every refresh request, e.g. a tenant ID some providers require.
+    /// The headers the flow sets itself (`Content-Type`, `Accept`, `User-Agent` and, with
+    /// `ClientAuthMethod::BasicHeader`, `Authorization`) replace custom ones of the same name.
+    pub fn with_headers(mut self, headers: Headers) -> RefreshFlow<C> {
+        self.headers = headers;
+        self
+    }
+
+    /// Append `params` to the form sent to the token endpoint, after the standard ones. Some
+    /// providers need additional parameters, like `audience` or `resource`.
+    ///
+    /// Parameters which the flow sets itself (`grant_type`, `refresh_token`, `client_id`,
+    /// `client_secret` and `scope`, which is set with `with_scopes()`) cannot be overridden;
+    /// they are dropped with a warning.
+    pub fn with_extra_params(mut self, params: Vec<(String, String)>) -> RefreshFlow<C> {
+        self.extra_params = filter_extra_params(params);
+        self
+    }
+
+    /// Ask for a token valid for `scopes` only, which must have been granted with the refresh
+    /// token. By default, the new token has the same scopes as the original one.
+    pub fn with_scopes<'a, I, T>(mut self, scopes: I) -> RefreshFlow<C>
+        where T: AsRef<str> + 'a,
+              I: IntoIterator<Item = &'a T>
+    {
+        self.scope = Some(scopes.into_iter()
+            .map(|s| s.as_ref())
+            .intersperse(" ")
+            .collect::<String>());
+        self
+    }
+
+    /// Call `hook` with the new token whenever a refresh succeeds, e.g. to persist it elsewhere
+    /// or to update a metric. It is not called if the refresh fails.
+    pub fn on_refresh(mut self, hook: Box<Fn(&Token)>) -> RefreshFlow<C> {
+        self.on_refresh = Some(hook);
+        self
+    }
+
+    /// Ask `clock` for the current time, from which the expiry of new tokens is computed, and
+    /// against which `ensure_valid()` checks tokens. By default, the system time is used.
+    pub fn with_clock(mut self, clock: Arc<Clock>) -> RefreshFlow<C> {
+        self.clock = clock;
+        self
+    }
+
+    /// Report every refresh to `metrics`. By default, nothing is reported.
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> RefreshFlow<C> {
+        self.metrics = metrics;
+        self
     }
 
     /// Attempt to refresh the given token, and obtain a new, valid one.
@@ -52,6 +289,9 @@ impl<C> RefreshFlow<C>
     /// * `client_id` & `client_secret` - as obtained when [registering your application](https://developers.google.com/youtube/registering_an_application)
     /// * `refresh_token` - obtained during previous call to `DeviceFlow::poll_token()` or equivalent
     ///
+    /// Tokens obtained through `FlowType::ServiceAccount` cannot be refreshed; a `RefreshError`
+    /// is returned without contacting the server.
+    ///
     /// # Examples
     /// Please see the crate landing page for an example.
     pub fn refresh_token(&mut self,
@@ -59,72 +299,527 @@ impl<C> RefreshFlow<C>
                          client_secret: &ApplicationSecret,
                          refresh_token: &str)
                          -> &RefreshResult {
-        let _ = flow_type;
-        if let RefreshResult::Success(_) = self.result {
-            return &self.result;
+        let result = match (self.result.take(), flow_type) {
+            (Some(RefreshResult::Success(token)), _) => RefreshResult::Success(token),
+            (_, FlowType::ServiceAccount(_)) => {
+                RefreshResult::RefreshError("unsupported_grant_type".to_string(),
+                                            Some("Service account tokens cannot be refreshed"
+                                                .to_string()))
+            }
+            _ => self.refresh(client_secret, refresh_token),
+        };
+        self.result.get_or_insert(result)
+    }
+
+    /// Like `refresh_token()`, but returns the new token, or the reason why there is none, as
+    /// a plain `Result`.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # extern crate hyper;
+    /// # extern crate yup_oauth2 as oauth2;
+    /// # fn main() {
+    /// # let secret = oauth2::Provider::google().application_secret("client-id", "secret");
+    /// let token = oauth2::RefreshFlow::new(hyper::Client::new())
+    ///     .obtain_refreshed(&secret, "1/6BMfW9j53gdGImsixUH6kU5RsR4zwI9lUVX-tqf8JXQ")
+    ///     .unwrap();
+    /// # }
+    /// ```
+    pub fn obtain_refreshed(mut self,
+                            client_secret: &ApplicationSecret,
+                            refresh_token: &str)
+                            -> Result<Token, error::Error> {
+        match self.result.take() {
+            Some(RefreshResult::Success(token)) => Ok(token),
+            _ => self.refresh(client_secret, refresh_token).into_result(),
         }
+    }
 
-        let req = form_urlencoded::serialize(&[("client_id", client_secret.client_id.as_ref()),
-                                               ("client_secret", client_secret.client_secret.as_ref()),
-                                               ("refresh_token", refresh_token),
-                                               ("grant_type", "refresh_token")]);
+    /// Returns `token` unchanged if it is still valid, or else a new one obtained with its
+    /// refresh token. Whether it is valid is decided like `Token::expired()` does, by the time
+    /// of the clock set with `with_clock()`.
+    ///
+    /// Unlike `refresh_token()`, every call with an expired token makes a new request.
+    pub fn ensure_valid(&mut self,
+                        client_secret: &ApplicationSecret,
+                        token: Token)
+                        -> Result<Token, error::Error> {
+        if !token.expired_with_clock(chrono::Duration::zero(), &*self.clock) {
+            return Ok(token);
+        }
+        self.refresh(client_secret, &token.refresh_token).into_result()
+    }
 
-        let json_str: String = match self.client
-            .borrow_mut()
-            .post(&client_secret.token_uri)
-            .header(ContentType("application/x-www-form-urlencoded".parse().unwrap()))
-            .body(&*req)
-            .send() {
-            Err(err) => {
-                self.result = RefreshResult::Error(err);
-                return &self.result;
+    /// Requests a new token, retrying as configured, and returns the outcome of the last attempt.
+    fn refresh(&mut self, client_secret: &ApplicationSecret, refresh_token: &str) -> RefreshResult {
+        debug!("Refreshing token at {}",
+               self.token_uri.as_ref().unwrap_or(&client_secret.token_uri));
+        self.metrics.on_refresh_start();
+        let start = Instant::now();
+        let mut attempt = 0;
+        loop {
+            let result = self.request_token(client_secret, refresh_token);
+            attempt += 1;
+            if let RefreshResult::Success(ref token) = result {
+                debug!("Token refreshed after {} attempt(s)", attempt);
+                self.metrics.on_refresh_success(start.elapsed());
+                if let Some(ref hook) = self.on_refresh {
+                    hook(token);
+                }
+                return result;
+            }
+            let max_delay = Duration::from_secs(MAX_RETRY_DELAY_SECS);
+            let too_long = match result {
+                RefreshResult::RateLimited(delay) => delay > max_delay,
+                _ => false,
+            };
+            if attempt >= self.max_attempts || !result.is_transient() || too_long {
+                error!("Token refresh failed: {:?}", result);
+                if let Some(err) = result.to_error() {
+                    self.metrics.on_refresh_failure(&err);
+                }
+                return result;
             }
+            warn!("Token refresh attempt {} of {} failed, retrying: {:?}",
+                  attempt,
+                  self.max_attempts,
+                  result);
+            sleep(match result {
+                RefreshResult::RateLimited(delay) if delay > Duration::from_secs(0) => delay,
+                _ => backoff_delay(self.base_delay, attempt),
+            });
+        }
+    }
+
+    /// Performs a single request to the token endpoint.
+    fn request_token(&mut self,
+                     client_secret: &ApplicationSecret,
+                     refresh_token: &str)
+                     -> RefreshResult {
+        if !self.insecure_http {
+            let token_uri = self.token_uri.as_ref().unwrap_or(&client_secret.token_uri);
+            if ensure_https(token_uri).is_err() {
+                return RefreshResult::InsecureUri(token_uri.clone());
+            }
+        }
+
+        let mut params = vec![("client_id", client_secret.client_id.as_str())];
+        if self.client_auth == ClientAuthMethod::RequestBody {
+            params.push(("client_secret", client_secret.client_secret.as_str()));
+        }
+        params.push(("refresh_token", refresh_token));
+        params.push(("grant_type", "refresh_token"));
+        if let Some(ref scope) = self.scope {
+            params.push(("scope", scope));
+        }
+        for &(ref name, ref value) in &self.extra_params {
+            params.push((name.as_str(), value.as_str()));
+        }
+        let req = form_urlencoded::serialize(&params);
+
+        if let Some(timeout) = self.timeout {
+            let client = self.client.borrow_mut();
+            client.set_read_timeout(Some(timeout));
+            client.set_write_timeout(Some(timeout));
+        }
+
+        let client = self.client.borrow_mut();
+        let token_uri = self.token_uri.as_ref().unwrap_or(&client_secret.token_uri);
+        let mut request = client.post(token_uri)
+            .headers(self.headers.clone())
+            .header(ContentType("application/x-www-form-urlencoded".parse().unwrap()))
+            .header(Accept::json())
+            .header(UserAgent(self.user_agent.clone()))
+            .body(&*req);
+        if self.client_auth == ClientAuthMethod::BasicHeader {
+            request = request.header(Authorization(Basic {
+                username: client_secret.client_id.clone(),
+                password: Some(client_secret.client_secret.clone()),
+            }));
+        }
+
+        let (status, json_str) = match request.send() {
+            Err(err) => return RefreshResult::from_http_error(err),
             Ok(mut res) => {
                 let mut json_str = String::new();
-                res.read_to_string(&mut json_str).unwrap();
-                json_str
+                if let Err(err) = res.read_to_string(&mut json_str) {
+                    return RefreshResult::from_http_error(hyper::Error::Io(err));
+                }
+                if res.status == StatusCode::TooManyRequests {
+                    let delay = res.headers
+                        .get_raw("Retry-After")
+                        .and_then(|values| values.first())
+                        .and_then(|value| String::from_utf8(value.clone()).ok())
+                        .and_then(|value| parse_retry_after(&value, self.clock.now()));
+                    return RefreshResult::RateLimited(delay.unwrap_or(Duration::from_secs(0)));
+                }
+                (res.status, json_str)
             }
         };
 
-        #[derive(Deserialize)]
-        struct JsonToken {
-            access_token: String,
-            token_type: String,
-            expires_in: i64,
+        // The server explains a refusal (e.g. `invalid_grant` for a revoked refresh token)
+        // in the body of a 4xx reply.
+        if status.is_client_error() {
+            return match json::from_str::<JsonError>(&json_str) {
+                Ok(res) => RefreshResult::RefreshError(res.error, res.error_description),
+                Err(_) => RefreshResult::RefreshError(status.to_string(), None),
+            };
+        }
+        if status.is_server_error() {
+            return RefreshResult::ServerError(status);
+        }
+        if !status.is_success() {
+            return RefreshResult::InvalidResponse(format!("Unexpected status {}, got: {}",
+                                                          status,
+                                                          body_snippet(&json_str)));
         }
 
-        match json::from_str::<JsonError>(&json_str) {
-            Err(_) => {}
-            Ok(res) => {
-                self.result = RefreshResult::RefreshError(res.error, res.error_description);
-                return &self.result;
+        let parsed = json::from_str(&json_str)
+            .map_err(error::Error::from)
+            .and_then(|value| Token::from_token_response_with_clock(&value, &*self.clock));
+        match parsed {
+            Ok(mut token) => {
+                // Providers rotating refresh tokens send a new one, which replaces the old.
+                if token.refresh_token.is_empty() {
+                    token.refresh_token = refresh_token.to_string();
+                }
+                RefreshResult::Success(token)
+            }
+            Err(error::Error::AuthError(error, desc)) => RefreshResult::RefreshError(error, desc),
+            Err(err) => {
+                RefreshResult::InvalidResponse(format!("{}, got: {}",
+                                                       json_error_reason(&err),
+                                                       body_snippet(&json_str)))
             }
         }
+    }
+}
 
-        let t: JsonToken = json::from_str(&json_str).unwrap();
-        self.result = RefreshResult::Success(Token {
-            access_token: t.access_token,
-            token_type: t.token_type,
-            refresh_token: refresh_token.to_string(),
-            expires_in: None,
-            expires_in_timestamp: Some(UTC::now().timestamp() + t.expires_in),
-        });
+// The message of the `JsonError` a token response failed to parse with, without the prefix its
+// `Display` adds.
+pub fn json_error_reason(err: &error::Error) -> String {
+    match *err {
+        error::Error::JsonError(ref err) => err.to_string(),
+        ref err => err.to_string(),
+    }
+}
+
+// Builds a token from the body of a token response, like
+// `Token::from_token_response_with_clock()` does. Error responses remain `Error::AuthError`s,
+// while anything else not shaped like a token becomes an `Error::InvalidResponse` quoting `body`.
+pub fn token_from_body(body: &str, clock: &Clock) -> Result<Token, error::Error> {
+    json::from_str(body)
+        .map_err(error::Error::from)
+        .and_then(|value| Token::from_token_response_with_clock(&value, clock))
+        .map_err(|err| match err {
+            err @ error::Error::AuthError(_, _) => err,
+            err => {
+                error::Error::InvalidResponse(format!("{}, got: {}",
+                                                      json_error_reason(&err),
+                                                      body_snippet(body)))
+            }
+        })
+}
+
+// Longest part of a response body to include in error messages.
+const MAX_SNIPPET_LEN: usize = 256;
 
-        &self.result
+// Returns the beginning of `body`, for inclusion in error messages.
+pub fn body_snippet(body: &str) -> String {
+    if body.len() <= MAX_SNIPPET_LEN {
+        return body.to_string();
     }
+    let mut end = MAX_SNIPPET_LEN;
+    while !body.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}...", &body[..end])
 }
 
+// Parses the value of a `Retry-After` header, which is either a number of seconds or an
+// HTTP-date, into the time to wait from `now`. A date in the past means not to wait at all.
+fn parse_retry_after(value: &str, now: DateTime<UTC>) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    // HTTP-dates always are in GMT, which RFC 2822 understands as well.
+    DateTime::parse_from_rfc2822(value).ok().map(|date| {
+        let secs = date.timestamp() - now.timestamp();
+        Duration::from_secs(max(secs, 0) as u64)
+    })
+}
 
+/// Collects the options of a `RefreshFlow`, to create any number of flows with them.
+///
+/// # Examples
+/// ```
+/// # extern crate hyper;
+/// # extern crate yup_oauth2 as oauth2;
+/// use oauth2::{ClientAuthMethod, RefreshFlowBuilder};
+/// use std::time::Duration;
+///
+/// # fn main() {
+/// let flow = RefreshFlowBuilder::new()
+///     .with_timeout(Duration::from_secs(10))
+///     .with_retry(3, Duration::from_millis(500))
+///     .with_client_auth(ClientAuthMethod::BasicHeader)
+///     .build(hyper::Client::new());
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct RefreshFlowBuilder {
+    timeout: Option<Duration>,
+    max_attempts: u32,
+    base_delay: Duration,
+    client_auth: ClientAuthMethod,
+    token_uri: Option<String>,
+    insecure_http: bool,
+    user_agent: String,
+    headers: Headers,
+    extra_params: Vec<(String, String)>,
+    scope: Option<String>,
+}
+
+impl RefreshFlowBuilder {
+    pub fn new() -> RefreshFlowBuilder {
+        RefreshFlowBuilder {
+            timeout: None,
+            max_attempts: 1,
+            base_delay: Duration::from_secs(0),
+            client_auth: ClientAuthMethod::default(),
+            token_uri: None,
+            insecure_http: false,
+            user_agent: DEFAULT_USER_AGENT.to_string(),
+            headers: Headers::new(),
+            extra_params: Vec::new(),
+            scope: None,
+        }
+    }
+
+    /// See `RefreshFlow::with_timeout()`.
+    pub fn with_timeout(mut self, timeout: Duration) -> RefreshFlowBuilder {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// See `RefreshFlow::with_retry()`.
+    pub fn with_retry(mut self, max_attempts: u32, base_delay: Duration) -> RefreshFlowBuilder {
+        self.max_attempts = max(max_attempts, 1);
+        self.base_delay = base_delay;
+        self
+    }
+
+    /// See `RefreshFlow::with_client_auth()`.
+    pub fn with_client_auth(mut self, method: ClientAuthMethod) -> RefreshFlowBuilder {
+        self.client_auth = method;
+        self
+    }
+
+    /// See `RefreshFlow::with_token_uri()`.
+    pub fn with_token_uri(mut self, token_uri: String) -> RefreshFlowBuilder {
+        self.token_uri = Some(token_uri);
+        self
+    }
+
+    /// See `RefreshFlow::with_insecure_http()`.
+    pub fn with_insecure_http(mut self, allow: bool) -> RefreshFlowBuilder {
+        self.insecure_http = allow;
+        self
+    }
+
+    /// See `RefreshFlow::with_user_agent()`.
+    pub fn with_user_agent(mut self, user_agent: String) -> RefreshFlowBuilder {
+        self.user_agent = user_agent;
+        self
+    }
+
+    /// See `RefreshFlow::with_headers()`.
+    pub fn with_headers(mut self, headers: Headers) -> RefreshFlowBuilder {
+        self.headers = headers;
+        self
+    }
+
+    /// See `RefreshFlow::with_extra_params()`.
+    pub fn with_extra_params(mut self, params: Vec<(String, String)>) -> RefreshFlowBuilder {
+        self.extra_params = filter_extra_params(params);
+        self
+    }
+
+    /// See `RefreshFlow::with_scopes()`.
+    pub fn with_scopes<'a, I, T>(mut self, scopes: I) -> RefreshFlowBuilder
+        where T: AsRef<str> + 'a,
+              I: IntoIterator<Item = &'a T>
+    {
+        self.scope = Some(scopes.into_iter()
+            .map(|s| s.as_ref())
+            .intersperse(" ")
+            .collect::<String>());
+        self
+    }
+
+    /// Creates a flow sending its requests through `client`. A proxy, if needed, is configured
+    /// on the client, see `client_from_env()`.
+    pub fn build<C>(&self, client: C) -> RefreshFlow<C>
+        where C: BorrowMut<hyper::Client>
+    {
+        RefreshFlow {
+            client: client,
+            result: None,
+            timeout: self.timeout,
+            max_attempts: self.max_attempts,
+            base_delay: self.base_delay,
+            client_auth: self.client_auth,
+            token_uri: self.token_uri.clone(),
+            insecure_http: self.insecure_http,
+            user_agent: self.user_agent.clone(),
+            headers: self.headers.clone(),
+            extra_params: self.extra_params.clone(),
+            scope: self.scope.clone(),
+            on_refresh: None,
+            clock: Arc::new(SystemClock),
+            metrics: Arc::new(NoMetrics),
+        }
+    }
+}
+
+impl Default for RefreshFlowBuilder {
+    fn default() -> RefreshFlowBuilder {
+        RefreshFlowBuilder::new()
+    }
+}
+
+// The parameters of a refresh request which are set by the flow itself.
+const RESERVED_PARAMS: [&'static str; 5] = ["client_id", "client_secret", "grant_type",
+                                            "refresh_token", "scope"];
+
+fn filter_extra_params(params: Vec<(String, String)>) -> Vec<(String, String)> {
+    params.into_iter()
+        .filter(|&(ref name, _)| {
+            let reserved = RESERVED_PARAMS.contains(&name.as_str());
+            if reserved {
+                warn!("Ignoring extra parameter {}, which is set by the refresh flow", name);
+            }
+            !reserved
+        })
+        .collect()
+}
+
+// The longest a retry waits for, before the jitter is added.
+const MAX_RETRY_DELAY_SECS: u64 = 300;
+
+/// Returns the time to wait before the given retry: the base delay doubles with every attempt,
+/// up to `MAX_RETRY_DELAY_SECS`, plus a random jitter of up to half of that, so that many
+/// clients don't retry in lockstep.
+fn backoff_delay(base_delay: Duration, attempt: u32) -> Duration {
+    let max_delay = Duration::from_secs(MAX_RETRY_DELAY_SECS);
+    let delay = base_delay.checked_mul(1u32 << min(attempt.saturating_sub(1), 31))
+        .map_or(max_delay, |delay| min(delay, max_delay));
+    let half_ms = delay.as_secs() * 500 + (delay.subsec_nanos() / 2_000_000) as u64;
+    delay + Duration::from_millis(rand::thread_rng().gen_range(0, half_ms + 1))
+}
 
 #[cfg(test)]
-mod tests {
+pub mod tests {
     use hyper;
     use std::default::Default;
     use super::*;
     use super::super::FlowType;
+    use hyper::status::StatusCode;
+    use types::tests::TEST_APP_SECRET;
     use yup_hyper_mock::{MockStream, SequentialConnector};
         use helper::parse_application_secret;
         use device::GOOGLE_DEVICE_CODE_URL;
+    use base64;
+    use log;
+    use std::cell::RefCell;
+    use std::io::{self, Cursor, Read, Write};
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex, Once};
+    use std::time::Duration;
+
+    /// A stream replying with a canned response, recording everything written to it.
+    pub struct RecordingStream {
+        read: Cursor<Vec<u8>>,
+        requests: Arc<Mutex<Vec<String>>>,
+        index: usize,
+    }
+
+    impl Read for RecordingStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.read.read(buf)
+        }
+    }
+
+    impl Write for RecordingStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.requests.lock().unwrap()[self.index].push_str(&String::from_utf8_lossy(buf));
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl hyper::net::NetworkStream for RecordingStream {
+        fn peer_addr(&mut self) -> io::Result<SocketAddr> {
+            Ok("127.0.0.1:1337".parse().unwrap())
+        }
+
+        fn set_read_timeout(&self, _: Option<Duration>) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn set_write_timeout(&self, _: Option<Duration>) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Answers each connection with the next of the given replies, and keeps the raw
+    /// requests for inspection by the test.
+    pub struct RecordingConnector {
+        replies: Vec<String>,
+        pub requests: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl RecordingConnector {
+        pub fn new(replies: &[&str]) -> RecordingConnector {
+            RecordingConnector {
+                replies: replies.iter().map(|r| r.to_string()).collect(),
+                requests: Arc::new(Mutex::new(Vec::new())),
+            }
+        }
+    }
+
+    impl hyper::net::NetworkConnector for RecordingConnector {
+        type Stream = RecordingStream;
+
+        fn connect(&self, _: &str, _: u16, _: &str) -> ::hyper::Result<RecordingStream> {
+            let mut requests = self.requests.lock().unwrap();
+            requests.push(String::new());
+            let index = requests.len() - 1;
+            Ok(RecordingStream {
+                read: Cursor::new(self.replies[index].clone().into_bytes()),
+                requests: self.requests.clone(),
+                index: index,
+            })
+        }
+    }
+
+    pub const TOKEN_REPLY: &'static str = "HTTP/1.1 200 OK\r\n\
+                                           Server: BOGUS\r\n\
+                                           \r\n\
+                                           {\"access_token\":\"1/fFAGRNJru1FTz70BzhT3Zg\",\
+                                           \"expires_in\":3920,\"token_type\":\"Bearer\"}";
+
+    fn client_with_replies(replies: &[&str]) -> hyper::Client {
+        let mut c = SequentialConnector::default();
+        for r in replies {
+            c.content.push(r.to_string());
+        }
+        hyper::Client::with_connector(c)
+    }
 
     struct MockGoogleRefresh(SequentialConnector);
 
@@ -153,8 +848,6 @@ mod tests {
         }
     }
 
-    const TEST_APP_SECRET: &'static str = r#"{"installed":{"client_id":"384278056379-tr5pbot1mil66749n639jo54i4840u77.apps.googleusercontent.com","project_id":"sanguine-rhythm-105020","auth_uri":"https://accounts.google.com/o/oauth2/auth","token_uri":"https://accounts.google.com/o/oauth2/token","auth_provider_x509_cert_url":"https://www.googleapis.com/oauth2/v1/certs","client_secret":"QeQUnhzsiO4t--ZGmj9muUAu","redirect_uris":["urn:ietf:wg:oauth:2.0:oob","http://localhost"]}}"#;
-
     #[test]
     fn refresh_flow() {
 
@@ -167,9 +860,713 @@ mod tests {
         match *flow.refresh_token(FlowType::Device(GOOGLE_DEVICE_CODE_URL.to_string()), &appsecret, "bogus_refresh_token") {
             RefreshResult::Success(ref t) => {
                 assert_eq!(t.access_token, "1/fFAGRNJru1FTz70BzhT3Zg");
+                assert_eq!(t.expires_in, Some(3920));
+                let issued_at = t.issued_at().unwrap();
+                assert!((UTC::now() - issued_at).num_seconds().abs() <= 5);
+                assert_eq!(t.expires_in_timestamp, Some(issued_at.timestamp() + 3920));
                 assert!(!t.expired());
+                assert_eq!(t.scopes, None);
             }
             _ => unreachable!(),
         }
     }
+
+    #[test]
+    fn refresh_flow_scopes() {
+        let appsecret = parse_application_secret(&TEST_APP_SECRET.to_string()).unwrap();
+        let mut c = client_with_replies(&["HTTP/1.1 200 OK\r\n\
+                                           Server: BOGUS\r\n\
+                                           \r\n\
+                                           {\"access_token\":\"1/fFAGRNJru1FTz70BzhT3Zg\",\
+                                           \"expires_in\":3920,\"token_type\":\"Bearer\",\
+                                           \"scope\":\"a b c\"}"]);
+        let mut flow = RefreshFlow::new(&mut c);
+        match *flow.refresh_token(FlowType::Device(GOOGLE_DEVICE_CODE_URL.to_string()), &appsecret, "bogus_refresh_token") {
+            RefreshResult::Success(ref t) => {
+                assert_eq!(t.scopes,
+                           Some(vec!["a".to_string(), "b".to_string(), "c".to_string()]))
+            }
+            ref r => panic!("Expected a Success, got {:?}", r),
+        }
+    }
+
+    thread_local! {
+        static LOG_LINES: RefCell<Vec<String>> = RefCell::new(Vec::new());
+    }
+
+    /// Collects the messages logged by the current thread, so that tests running in parallel
+    /// don't see each other's messages.
+    struct CapturingLogger;
+
+    impl log::Log for CapturingLogger {
+        fn enabled(&self, _: &log::LogMetadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::LogRecord) {
+            let line = format!("{} {}", record.level(), record.args());
+            LOG_LINES.with(|lines| lines.borrow_mut().push(line));
+        }
+    }
+
+    fn capture_logs() {
+        static INIT: Once = Once::new();
+        INIT.call_once(|| {
+            log::set_logger(|max_level| {
+                    max_level.set(log::LogLevelFilter::Debug);
+                    Box::new(CapturingLogger)
+                })
+                .unwrap()
+        });
+        LOG_LINES.with(|lines| lines.borrow_mut().clear());
+    }
+
+    #[test]
+    fn refresh_flow_logs() {
+        capture_logs();
+        let appsecret = parse_application_secret(&TEST_APP_SECRET.to_string()).unwrap();
+        let mut c = client_with_replies(&[TOKEN_REPLY]);
+        let mut flow = RefreshFlow::new(&mut c);
+        flow.refresh_token(FlowType::Device(GOOGLE_DEVICE_CODE_URL.to_string()), &appsecret, "bogus_refresh_token");
+
+        let lines = LOG_LINES.with(|lines| lines.borrow().clone());
+        assert!(lines.contains(&"DEBUG Refreshing token at https://accounts.google.com/o/oauth2/token"
+            .to_string()));
+        assert!(lines.contains(&"DEBUG Token refreshed after 1 attempt(s)".to_string()));
+        // No secrets in the logs
+        for line in lines {
+            assert!(!line.contains("bogus_refresh_token"));
+            assert!(!line.contains("1/fFAGRNJru1FTz70BzhT3Zg"));
+            assert!(!line.contains(&appsecret.client_secret));
+        }
+    }
+
+    #[test]
+    fn refresh_flow_invalid_grant() {
+        let appsecret = parse_application_secret(&TEST_APP_SECRET.to_string()).unwrap();
+
+        let mut c = client_with_replies(&["HTTP/1.1 400 Bad Request\r\n\
+                                           Server: BOGUS\r\n\
+                                           \r\n\
+                                          {\r\n\
+                                            \"error\":\"invalid_grant\",\r\n\
+                                            \"error_description\":\"Token has been expired or revoked.\"\r\n\
+                                          }"]);
+        let mut flow = RefreshFlow::new(&mut c);
+
+        let result = flow.refresh_token(FlowType::Device(GOOGLE_DEVICE_CODE_URL.to_string()), &appsecret, "bogus_refresh_token");
+        assert!(result.needs_reauth());
+        match *result {
+            RefreshResult::RefreshError(ref err, ref desc) => {
+                assert_eq!(err, "invalid_grant");
+                assert_eq!(desc.as_ref().map(|d| d.as_str()), Some("Token has been expired or revoked."));
+            }
+            ref r => panic!("Expected a RefreshError, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn obtain_refreshed() {
+        let appsecret = parse_application_secret(&TEST_APP_SECRET.to_string()).unwrap();
+
+        let mut c = client_with_replies(&[TOKEN_REPLY]);
+        let token = RefreshFlow::new(&mut c)
+            .obtain_refreshed(&appsecret, "bogus_refresh_token")
+            .unwrap();
+        assert_eq!(token.access_token, "1/fFAGRNJru1FTz70BzhT3Zg");
+        assert_eq!(token.refresh_token, "bogus_refresh_token");
+
+        let mut c = client_with_replies(&["HTTP/1.1 400 Bad Request\r\n\
+                                           Server: BOGUS\r\n\
+                                           \r\n\
+                                           {\"error\":\"invalid_grant\"}"]);
+        match RefreshFlow::new(&mut c).obtain_refreshed(&appsecret, "bogus_refresh_token") {
+            Err(ref err @ error::Error::AuthError(_, None)) => assert!(err.needs_reauth()),
+            r => panic!("Expected an AuthError, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn refresh_flow_server_error() {
+        let appsecret = parse_application_secret(&TEST_APP_SECRET.to_string()).unwrap();
+
+        let mut c = client_with_replies(&["HTTP/1.1 503 Service Unavailable\r\n\
+                                           Server: BOGUS\r\n\
+                                           \r\n\
+                                           <html>Try again later</html>"]);
+        let mut flow = RefreshFlow::new(&mut c);
+
+        match *flow.refresh_token(FlowType::Device(GOOGLE_DEVICE_CODE_URL.to_string()), &appsecret, "bogus_refresh_token") {
+            RefreshResult::ServerError(status) => assert_eq!(status, StatusCode::ServiceUnavailable),
+            _ => panic!("Expected a ServerError"),
+        }
+    }
+
+    #[test]
+    fn refresh_result_into_result() {
+        let token = Token::new("1/fFAGRNJru1FTz70BzhT3Zg", "Bearer");
+        assert_eq!(RefreshResult::Success(token.clone()).into_result().unwrap(), token);
+
+        let refused = RefreshResult::RefreshError("invalid_grant".to_string(),
+                                                  Some("Token has been revoked.".to_string()));
+        assert!(refused.needs_reauth());
+        assert!(!RefreshResult::RefreshError("invalid_client".to_string(), None).needs_reauth());
+        assert!(!RefreshResult::ServerError(StatusCode::BadGateway).needs_reauth());
+        match refused.into_result() {
+            Err(error::Error::AuthError(ref err, Some(ref desc))) => {
+                assert_eq!(err, "invalid_grant");
+                assert_eq!(desc, "Token has been revoked.");
+            }
+            r => panic!("Expected an AuthError, got {:?}", r),
+        }
+
+        let result: Result<Token, error::Error> =
+            RefreshResult::ServerError(StatusCode::BadGateway).into();
+        match result {
+            Err(error::Error::BadStatus(StatusCode::BadGateway)) => {}
+            r => panic!("Expected a BadStatus, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn refresh_flow_rate_limited() {
+        let appsecret = parse_application_secret(&TEST_APP_SECRET.to_string()).unwrap();
+
+        let mut c = client_with_replies(&["HTTP/1.1 429 Too Many Requests\r\n\
+                                           Server: BOGUS\r\n\
+                                           Retry-After: 30\r\n\
+                                           \r\n"]);
+        let mut flow = RefreshFlow::new(&mut c);
+        match *flow.refresh_token(FlowType::Device(GOOGLE_DEVICE_CODE_URL.to_string()), &appsecret, "bogus_refresh_token") {
+            RefreshResult::RateLimited(delay) => assert_eq!(delay, Duration::from_secs(30)),
+            ref r => panic!("Expected RateLimited, got {:?}", r),
+        }
+
+        let mut c = client_with_replies(&["HTTP/1.1 429 Too Many Requests\r\n\r\n"]);
+        match RefreshFlow::new(&mut c).obtain_refreshed(&appsecret, "bogus_refresh_token") {
+            Err(error::Error::RateLimited(delay)) => assert_eq!(delay, Duration::from_secs(0)),
+            r => panic!("Expected RateLimited, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn refresh_flow_retries_after_rate_limit() {
+        use std::time::Instant;
+
+        let appsecret = parse_application_secret(&TEST_APP_SECRET.to_string()).unwrap();
+        let mut c = client_with_replies(&["HTTP/1.1 429 Too Many Requests\r\n\
+                                           Retry-After: 1\r\n\
+                                           \r\n",
+                                          TOKEN_REPLY]);
+        let mut flow = RefreshFlow::new(&mut c).with_retry(2, Duration::from_millis(1));
+        let start = Instant::now();
+        match *flow.refresh_token(FlowType::Device(GOOGLE_DEVICE_CODE_URL.to_string()), &appsecret, "bogus_refresh_token") {
+            RefreshResult::Success(ref t) => assert_eq!(t.access_token, "1/fFAGRNJru1FTz70BzhT3Zg"),
+            ref r => panic!("Expected the second attempt to succeed, got {:?}", r),
+        }
+        assert!(start.elapsed() >= Duration::from_secs(1));
+
+        // The flow doesn't wait for an hour, but leaves that up to the caller.
+        let mut c = client_with_replies(&["HTTP/1.1 429 Too Many Requests\r\n\
+                                           Retry-After: 3600\r\n\
+                                           \r\n",
+                                          TOKEN_REPLY]);
+        let mut flow = RefreshFlow::new(&mut c).with_retry(2, Duration::from_millis(1));
+        match *flow.refresh_token(FlowType::Device(GOOGLE_DEVICE_CODE_URL.to_string()), &appsecret, "bogus_refresh_token") {
+            RefreshResult::RateLimited(delay) => assert_eq!(delay, Duration::from_secs(3600)),
+            ref r => panic!("Expected RateLimited, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn retry_after_values() {
+        use chrono::TimeZone;
+
+        let now = UTC.ymd(2015, 10, 21).and_hms(7, 28, 0);
+        assert_eq!(super::parse_retry_after("120", now), Some(Duration::from_secs(120)));
+        assert_eq!(super::parse_retry_after("Wed, 21 Oct 2015 07:28:30 GMT", now),
+                   Some(Duration::from_secs(30)));
+        assert_eq!(super::parse_retry_after("Wed, 21 Oct 2015 07:00:00 GMT", now),
+                   Some(Duration::from_secs(0)));
+        assert_eq!(super::parse_retry_after("soon", now), None);
+    }
+
+    #[test]
+    fn refresh_flow_invalid_response() {
+        let appsecret = parse_application_secret(&TEST_APP_SECRET.to_string()).unwrap();
+
+        let mut c = client_with_replies(&["HTTP/1.1 200 OK\r\n\
+                                           Server: BOGUS\r\n\
+                                           \r\n\
+                                           {\"accessToken\": \"1/fFAGRNJru1FTz70BzhT3Zg\"}"]);
+        let mut flow = RefreshFlow::new(&mut c);
+
+        match *flow.refresh_token(FlowType::Device(GOOGLE_DEVICE_CODE_URL.to_string()), &appsecret, "bogus_refresh_token") {
+            RefreshResult::InvalidResponse(ref reason) => {
+                assert!(reason.contains("access_token"));
+                assert!(reason.ends_with("got: {\"accessToken\": \"1/fFAGRNJru1FTz70BzhT3Zg\"}"));
+            }
+            ref r => panic!("Expected an InvalidResponse, got {:?}", r),
+        }
+
+        let long_body = format!("<html>{}</html>", "x".repeat(1000));
+        assert_eq!(super::body_snippet(&long_body).len(), super::MAX_SNIPPET_LEN + 3);
+        assert!(super::body_snippet(&long_body).ends_with("xxx..."));
+    }
+
+    #[test]
+    fn backoff_delay_is_bounded() {
+        let base = Duration::from_secs(1);
+        let delay = super::backoff_delay(base, 3);
+        assert!(delay >= Duration::from_secs(4) && delay <= Duration::from_secs(6));
+
+        let max_delay = Duration::from_secs(super::MAX_RETRY_DELAY_SECS);
+        for &attempt in &[10, 32, 33, 1000, u32::max_value()] {
+            let delay = super::backoff_delay(base, attempt);
+            assert!(delay >= max_delay && delay <= max_delay * 3 / 2);
+        }
+        assert!(super::backoff_delay(Duration::from_secs(u64::max_value()), 2) <= max_delay * 3 / 2);
+    }
+
+    #[test]
+    fn refresh_flow_timeout() {
+        use std::net::TcpListener;
+
+        // Connections are accepted by the OS, but nobody ever answers them.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut appsecret = parse_application_secret(&TEST_APP_SECRET.to_string()).unwrap();
+        appsecret.token_uri = format!("http://{}/token", listener.local_addr().unwrap());
+
+        let mut flow = RefreshFlow::new(hyper::Client::new())
+            .with_timeout(Duration::from_millis(100))
+            .with_insecure_http(true);
+
+        match *flow.refresh_token(FlowType::Device(GOOGLE_DEVICE_CODE_URL.to_string()), &appsecret, "bogus_refresh_token") {
+            RefreshResult::Timeout => {}
+            _ => panic!("Expected a Timeout"),
+        }
+    }
+
+    #[test]
+    fn refresh_flow_retries_server_errors() {
+        let appsecret = parse_application_secret(&TEST_APP_SECRET.to_string()).unwrap();
+        let unavailable = "HTTP/1.1 503 Service Unavailable\r\nServer: BOGUS\r\n\r\n";
+
+        let mut c = client_with_replies(&[unavailable, TOKEN_REPLY]);
+        let mut flow = RefreshFlow::new(&mut c).with_retry(3, Duration::from_millis(1));
+        match *flow.refresh_token(FlowType::Device(GOOGLE_DEVICE_CODE_URL.to_string()), &appsecret, "bogus_refresh_token") {
+            RefreshResult::Success(ref t) => assert_eq!(t.access_token, "1/fFAGRNJru1FTz70BzhT3Zg"),
+            _ => panic!("Expected the second attempt to succeed"),
+        }
+
+        // The mock panics if it is asked for more than the three given replies.
+        let mut c = client_with_replies(&[unavailable, unavailable, unavailable]);
+        let mut flow = RefreshFlow::new(&mut c).with_retry(3, Duration::from_millis(1));
+        match *flow.refresh_token(FlowType::Device(GOOGLE_DEVICE_CODE_URL.to_string()), &appsecret, "bogus_refresh_token") {
+            RefreshResult::ServerError(status) => assert_eq!(status, StatusCode::ServiceUnavailable),
+            _ => panic!("Expected the last attempt's ServerError"),
+        }
+    }
+
+    #[test]
+    fn refresh_flow_does_not_retry_refusals() {
+        let appsecret = parse_application_secret(&TEST_APP_SECRET.to_string()).unwrap();
+        let mut c = client_with_replies(&["HTTP/1.1 400 Bad Request\r\n\
+                                           Server: BOGUS\r\n\
+                                           \r\n\
+                                          {\"error\":\"invalid_grant\"}"]);
+        let mut flow = RefreshFlow::new(&mut c).with_retry(3, Duration::from_millis(1));
+        match *flow.refresh_token(FlowType::Device(GOOGLE_DEVICE_CODE_URL.to_string()), &appsecret, "bogus_refresh_token") {
+            RefreshResult::RefreshError(ref err, _) => assert_eq!(err, "invalid_grant"),
+            ref r => panic!("Expected a RefreshError, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn refresh_flow_token_uri() {
+        let appsecret = parse_application_secret(&TEST_APP_SECRET.to_string()).unwrap();
+        let connector = RecordingConnector::new(&[TOKEN_REPLY]);
+        let requests = connector.requests.clone();
+        let mut c = hyper::Client::with_connector(connector);
+        let mut flow = RefreshFlow::new(&mut c)
+            .with_token_uri("https://idp.example.com/oauth/token".to_string());
+        match *flow.refresh_token(FlowType::Device(GOOGLE_DEVICE_CODE_URL.to_string()), &appsecret, "bogus_refresh_token") {
+            RefreshResult::Success(_) => {}
+            ref r => panic!("Expected a Success, got {:?}", r),
+        }
+
+        let request = requests.lock().unwrap()[0].clone();
+        assert!(request.starts_with("POST /oauth/token HTTP/1.1\r\n"));
+        assert!(request.contains("Host: idp.example.com\r\n"));
+    }
+
+    #[test]
+    fn refresh_flow_rejects_http_token_uri() {
+        let mut appsecret = parse_application_secret(&TEST_APP_SECRET.to_string()).unwrap();
+        appsecret.token_uri = "http://accounts.google.com/o/oauth2/token".to_string();
+        let connector = RecordingConnector::new(&[TOKEN_REPLY]);
+        let requests = connector.requests.clone();
+        let mut c = hyper::Client::with_connector(connector);
+
+        match RefreshFlow::new(&mut c).obtain_refreshed(&appsecret, "bogus_refresh_token") {
+            Err(error::Error::InsecureUri(ref uri)) => assert_eq!(uri, &appsecret.token_uri),
+            r => panic!("Expected an InsecureUri error, got {:?}", r),
+        }
+        // Not even `http://localhost` is trusted with the refresh token.
+        match RefreshFlow::new(&mut c)
+            .with_token_uri("http://localhost:8080/token".to_string())
+            .obtain_refreshed(&appsecret, "bogus_refresh_token") {
+            Err(error::Error::InsecureUri(_)) => {}
+            r => panic!("Expected an InsecureUri error, got {:?}", r),
+        }
+        assert!(requests.lock().unwrap().is_empty());
+
+        let token = RefreshFlow::new(&mut c)
+            .with_insecure_http(true)
+            .obtain_refreshed(&appsecret, "bogus_refresh_token")
+            .unwrap();
+        assert_eq!(token.access_token, "1/fFAGRNJru1FTz70BzhT3Zg");
+        assert_eq!(requests.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn refresh_flow_user_agent() {
+        let appsecret = parse_application_secret(&TEST_APP_SECRET.to_string()).unwrap();
+        let connector = RecordingConnector::new(&[TOKEN_REPLY, TOKEN_REPLY]);
+        let requests = connector.requests.clone();
+        let mut c = hyper::Client::with_connector(connector);
+
+        RefreshFlow::new(&mut c)
+            .refresh_token(FlowType::Device(GOOGLE_DEVICE_CODE_URL.to_string()), &appsecret, "bogus_refresh_token");
+        RefreshFlow::new(&mut c)
+            .with_user_agent("my-app/0.1".to_string())
+            .refresh_token(FlowType::Device(GOOGLE_DEVICE_CODE_URL.to_string()), &appsecret, "bogus_refresh_token");
+
+        let requests = requests.lock().unwrap();
+        assert!(requests[0].contains(&format!("User-Agent: yup-oauth2/{}\r\n",
+                                              env!("CARGO_PKG_VERSION"))));
+        assert!(requests[1].contains("User-Agent: my-app/0.1\r\n"));
+    }
+
+    #[test]
+    fn refresh_flow_headers() {
+        let appsecret = parse_application_secret(&TEST_APP_SECRET.to_string()).unwrap();
+        let connector = RecordingConnector::new(&[TOKEN_REPLY]);
+        let requests = connector.requests.clone();
+        let mut c = hyper::Client::with_connector(connector);
+
+        let mut headers = Headers::new();
+        headers.set_raw("X-Tenant-Id", vec![b"contoso".to_vec()]);
+        headers.set(ContentType::json());
+        headers.set(Authorization(Basic {
+            username: "somebody-else".to_string(),
+            password: None,
+        }));
+        RefreshFlow::new(&mut c)
+            .with_client_auth(ClientAuthMethod::BasicHeader)
+            .with_headers(headers)
+            .refresh_token(FlowType::Device(GOOGLE_DEVICE_CODE_URL.to_string()), &appsecret, "bogus_refresh_token");
+
+        let request = requests.lock().unwrap()[0].clone();
+        assert!(request.contains("X-Tenant-Id: contoso\r\n"));
+        // The flow's own headers win.
+        assert!(request.contains("Content-Type: application/x-www-form-urlencoded\r\n"));
+        assert!(!request.contains("Content-Type: application/json"));
+        assert!(request.contains(&format!("Authorization: Basic {}\r\n",
+                                          base64::encode(&format!("{}:{}",
+                                                                  appsecret.client_id,
+                                                                  appsecret.client_secret)))));
+    }
+
+    #[test]
+    fn refresh_flow_extra_params() {
+        let appsecret = parse_application_secret(&TEST_APP_SECRET.to_string()).unwrap();
+        let connector = RecordingConnector::new(&[TOKEN_REPLY]);
+        let requests = connector.requests.clone();
+        let mut c = hyper::Client::with_connector(connector);
+
+        RefreshFlow::new(&mut c)
+            .with_extra_params(vec![("audience".to_string(), "foo".to_string()),
+                                    ("grant_type".to_string(), "password".to_string()),
+                                    ("scope".to_string(), "admin".to_string())])
+            .refresh_token(FlowType::Device(GOOGLE_DEVICE_CODE_URL.to_string()), &appsecret, "bogus_refresh_token");
+
+        let request = requests.lock().unwrap()[0].clone();
+        assert!(request.ends_with("&grant_type=refresh_token&audience=foo"));
+        assert!(!request.contains("grant_type=password"));
+        assert!(!request.contains("scope=admin"));
+    }
+
+    #[test]
+    fn refresh_flow_with_scopes() {
+        let appsecret = parse_application_secret(&TEST_APP_SECRET.to_string()).unwrap();
+        let connector = RecordingConnector::new(&[TOKEN_REPLY]);
+        let requests = connector.requests.clone();
+        let mut c = hyper::Client::with_connector(connector);
+
+        RefreshFlow::new(&mut c)
+            .with_scopes(&["a", "b"])
+            .refresh_token(FlowType::Device(GOOGLE_DEVICE_CODE_URL.to_string()), &appsecret, "bogus_refresh_token");
+
+        let request = requests.lock().unwrap()[0].clone();
+        assert!(request.ends_with("&grant_type=refresh_token&scope=a+b"));
+    }
+
+    #[test]
+    fn refresh_flow_on_refresh() {
+        use std::cell::Cell;
+        use std::rc::Rc;
+
+        let appsecret = parse_application_secret(&TEST_APP_SECRET.to_string()).unwrap();
+        let calls = Rc::new(Cell::new(0));
+
+        let hook_calls = calls.clone();
+        let mut c = client_with_replies(&[TOKEN_REPLY]);
+        let mut flow = RefreshFlow::new(&mut c).on_refresh(Box::new(move |t: &Token| {
+            assert_eq!(t.access_token, "1/fFAGRNJru1FTz70BzhT3Zg");
+            hook_calls.set(hook_calls.get() + 1);
+        }));
+        flow.refresh_token(FlowType::Device(GOOGLE_DEVICE_CODE_URL.to_string()), &appsecret, "bogus_refresh_token");
+        // The result is cached, so the hook isn't called again.
+        flow.refresh_token(FlowType::Device(GOOGLE_DEVICE_CODE_URL.to_string()), &appsecret, "bogus_refresh_token");
+        assert_eq!(calls.get(), 1);
+
+        let hook_calls = calls.clone();
+        let mut c = client_with_replies(&["HTTP/1.1 400 Bad Request\r\n\
+                                           \r\n\
+                                           {\"error\":\"invalid_grant\"}"]);
+        RefreshFlow::new(&mut c)
+            .on_refresh(Box::new(move |_: &Token| hook_calls.set(hook_calls.get() + 1)))
+            .refresh_token(FlowType::Device(GOOGLE_DEVICE_CODE_URL.to_string()), &appsecret, "bogus_refresh_token");
+        assert_eq!(calls.get(), 1);
+    }
+
+    #[test]
+    fn refresh_flow_rotated_refresh_token() {
+        let appsecret = parse_application_secret(&TEST_APP_SECRET.to_string()).unwrap();
+        let mut c = client_with_replies(&["HTTP/1.1 200 OK\r\n\
+                                           Server: BOGUS\r\n\
+                                           \r\n\
+                                           {\"access_token\":\"1/fFAGRNJru1FTz70BzhT3Zg\",\
+                                           \"refresh_token\":\"1/rotated_refresh_token\",\
+                                           \"expires_in\":3920,\"token_type\":\"Bearer\"}",
+                                          TOKEN_REPLY]);
+
+        let token = RefreshFlow::new(&mut c).obtain_refreshed(&appsecret, "bogus_refresh_token");
+        assert_eq!(token.unwrap().refresh_token, "1/rotated_refresh_token");
+        // Without a new one, the old refresh token remains valid.
+        let token = RefreshFlow::new(&mut c).obtain_refreshed(&appsecret, "bogus_refresh_token");
+        assert_eq!(token.unwrap().refresh_token, "bogus_refresh_token");
+    }
+
+    #[test]
+    fn refresh_flow_expires_in_as_string() {
+        let appsecret = parse_application_secret(&TEST_APP_SECRET.to_string()).unwrap();
+        let mut c = client_with_replies(&[TOKEN_REPLY,
+                                          "HTTP/1.1 200 OK\r\n\
+                                           Server: BOGUS\r\n\
+                                           \r\n\
+                                           {\"access_token\":\"1/fFAGRNJru1FTz70BzhT3Zg\",\
+                                           \"expires_in\":\"3920\",\"token_type\":\"Bearer\"}"]);
+
+        for _ in 0..2 {
+            let token = RefreshFlow::new(&mut c)
+                .obtain_refreshed(&appsecret, "bogus_refresh_token")
+                .unwrap();
+            assert_eq!(token.expires_in, Some(3920));
+        }
+    }
+
+    #[test]
+    fn refresh_flow_without_expires_in() {
+        let appsecret = parse_application_secret(&TEST_APP_SECRET.to_string()).unwrap();
+        let mut c = client_with_replies(&["HTTP/1.1 200 OK\r\n\
+                                           Server: BOGUS\r\n\
+                                           \r\n\
+                                           {\"access_token\":\"1/fFAGRNJru1FTz70BzhT3Zg\",\
+                                           \"token_type\":\"Bearer\"}"]);
+
+        let mut flow = RefreshFlow::new(&mut c);
+        let mut token = flow.ensure_valid(&appsecret,
+                          Token::new("1/expired", "Bearer")
+                              .with_refresh_token("bogus_refresh_token")
+                              .with_expires_in(-10))
+            .unwrap();
+        assert_eq!(token.access_token, "1/fFAGRNJru1FTz70BzhT3Zg");
+        assert_eq!(token.expires_in, None);
+        assert_eq!(token.expires_in_timestamp, None);
+        assert!(!token.expired());
+        assert_eq!(token.set_expiry_absolute().expires_in_timestamp, None);
+        // Without an expiry, the token stays valid; the mock has no second reply.
+        let token = flow.ensure_valid(&appsecret, token).unwrap();
+        assert_eq!(token.access_token, "1/fFAGRNJru1FTz70BzhT3Zg");
+    }
+
+    #[test]
+    fn ensure_valid() {
+        let appsecret = parse_application_secret(&TEST_APP_SECRET.to_string()).unwrap();
+        let token = Token::new("1/still_valid", "Bearer")
+            .with_refresh_token("bogus_refresh_token")
+            .with_expires_in(3600);
+
+        // The mock panics when asked for a reply it hasn't got.
+        let mut c = client_with_replies(&[]);
+        let same = RefreshFlow::new(&mut c).ensure_valid(&appsecret, token.clone()).unwrap();
+        assert_eq!(same, token);
+
+        let expired = token.with_expiry_timestamp(UTC::now().timestamp() - 10);
+        let mut c = client_with_replies(&[TOKEN_REPLY]);
+        let mut flow = RefreshFlow::new(&mut c);
+        let refreshed = flow.ensure_valid(&appsecret, expired).unwrap();
+        assert_eq!(refreshed.access_token, "1/fFAGRNJru1FTz70BzhT3Zg");
+        assert_eq!(refreshed.refresh_token, "bogus_refresh_token");
+        assert!(!refreshed.expired());
+
+        // With only a relative expiry, there is no telling whether the token is still valid.
+        let mut relative = Token::new("1/unknown", "Bearer")
+            .with_refresh_token("bogus_refresh_token");
+        relative.expires_in = Some(3600);
+        let mut c = client_with_replies(&[TOKEN_REPLY]);
+        let refreshed = RefreshFlow::new(&mut c).ensure_valid(&appsecret, relative).unwrap();
+        assert_eq!(refreshed.access_token, "1/fFAGRNJru1FTz70BzhT3Zg");
+
+        let mut c = client_with_replies(&["HTTP/1.1 400 Bad Request\r\n\
+                                           \r\n\
+                                           {\"error\":\"invalid_grant\"}"]);
+        let unrefreshable = Token::new("1/expired", "Bearer").with_expires_in(-10);
+        match RefreshFlow::new(&mut c).ensure_valid(&appsecret, unrefreshable) {
+            Err(error::Error::AuthError(ref err, _)) => assert_eq!(err, "invalid_grant"),
+            r => panic!("Expected an AuthError, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn refresh_flow_with_clock() {
+        use chrono::{self, TimeZone};
+        use clock::MockClock;
+
+        let appsecret = parse_application_secret(&TEST_APP_SECRET.to_string()).unwrap();
+        let clock = Arc::new(MockClock::new(UTC.ymd(2017, 7, 14).and_hms(2, 40, 0)));
+        let mut c = client_with_replies(&[TOKEN_REPLY]);
+        let mut flow = RefreshFlow::new(&mut c).with_clock(clock.clone());
+
+        let token = Token::new("1/old", "Bearer")
+            .with_refresh_token("bogus_refresh_token")
+            .with_expiry_timestamp(clock.now().timestamp() + 60);
+        // Still valid for the clock, so the mock doesn't hand out its token.
+        assert_eq!(flow.ensure_valid(&appsecret, token.clone()).unwrap().access_token, "1/old");
+
+        clock.advance(chrono::Duration::seconds(60));
+        let new = flow.ensure_valid(&appsecret, token).unwrap();
+        assert_eq!(new.access_token, "1/fFAGRNJru1FTz70BzhT3Zg");
+        assert_eq!(new.issued_at(), Some(clock.now()));
+        assert_eq!(new.expires_in_timestamp, Some(clock.now().timestamp() + 3920));
+        assert!(!new.expired_with_clock(chrono::Duration::zero(), &*clock));
+        clock.advance(chrono::Duration::seconds(3920));
+        assert!(new.expired_with_clock(chrono::Duration::zero(), &*clock));
+    }
+
+    #[test]
+    fn refresh_flow_builder() {
+        let appsecret = parse_application_secret(&TEST_APP_SECRET.to_string()).unwrap();
+        let basic_auth = format!("Authorization: Basic {}",
+                                 base64::encode(&format!("{}:{}",
+                                                         appsecret.client_id,
+                                                         appsecret.client_secret)));
+        let connector = RecordingConnector::new(&["HTTP/1.1 503 Service Unavailable\r\n\r\n",
+                                                  TOKEN_REPLY,
+                                                  TOKEN_REPLY]);
+        let requests = connector.requests.clone();
+        let mut c = hyper::Client::with_connector(connector);
+        let builder = RefreshFlowBuilder::new()
+            .with_retry(2, Duration::from_millis(1))
+            .with_client_auth(ClientAuthMethod::BasicHeader)
+            .with_token_uri("http://idp.example.com/oauth/token".to_string())
+            .with_insecure_http(true)
+            .with_user_agent("my-app/0.1".to_string())
+            .with_scopes(&["email"]);
+
+        // The same options apply to every flow built.
+        for _ in 0..2 {
+            match *builder.build(&mut c).refresh_token(FlowType::Device(GOOGLE_DEVICE_CODE_URL.to_string()), &appsecret, "bogus_refresh_token") {
+                RefreshResult::Success(_) => {}
+                ref r => panic!("Expected a Success, got {:?}", r),
+            }
+        }
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 3);
+        for request in requests.iter() {
+            assert!(request.starts_with("POST /oauth/token HTTP/1.1\r\n"));
+            assert!(request.contains(&basic_auth));
+            assert!(request.contains("User-Agent: my-app/0.1\r\n"));
+            assert!(request.ends_with("&scope=email"));
+        }
+    }
+
+    #[test]
+    fn refresh_flow_tls_client_auth() {
+        let appsecret = parse_application_secret(&TEST_APP_SECRET.to_string()).unwrap();
+        let connector = RecordingConnector::new(&[TOKEN_REPLY]);
+        let requests = connector.requests.clone();
+        let mut c = hyper::Client::with_connector(connector);
+
+        RefreshFlow::new(&mut c)
+            .with_client_auth(ClientAuthMethod::TlsClientAuth)
+            .refresh_token(FlowType::Device(GOOGLE_DEVICE_CODE_URL.to_string()), &appsecret, "bogus_refresh_token");
+
+        let request = requests.lock().unwrap()[0].clone();
+        assert!(!request.contains("Authorization:"));
+        assert!(request.contains(&appsecret.client_id));
+        assert!(!request.contains("client_secret"));
+        assert!(!request.contains(&appsecret.client_secret));
+    }
+
+    #[test]
+    fn refresh_flow_service_account() {
+        use helper::service_account_key_from_file;
+
+        let appsecret = parse_application_secret(&TEST_APP_SECRET.to_string()).unwrap();
+        let key = service_account_key_from_file(&"examples/Sanguine-69411a0c0eea.json".to_string())
+            .unwrap();
+        let connector = RecordingConnector::new(&[TOKEN_REPLY]);
+        let requests = connector.requests.clone();
+        let mut c = hyper::Client::with_connector(connector);
+        let mut flow = RefreshFlow::new(&mut c);
+        match *flow.refresh_token(FlowType::ServiceAccount(key), &appsecret, "") {
+            RefreshResult::RefreshError(ref err, _) => assert_eq!(err, "unsupported_grant_type"),
+            ref r => panic!("Expected a RefreshError, got {:?}", r),
+        }
+        assert!(requests.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn refresh_flow_client_auth() {
+        let appsecret = parse_application_secret(&TEST_APP_SECRET.to_string()).unwrap();
+        let basic_auth = format!("Authorization: Basic {}",
+                                 base64::encode(&format!("{}:{}",
+                                                         appsecret.client_id,
+                                                         appsecret.client_secret)));
+
+        let connector = RecordingConnector::new(&[TOKEN_REPLY]);
+        let requests = connector.requests.clone();
+        let mut flow = RefreshFlow::new(hyper::Client::with_connector(connector));
+        match *flow.refresh_token(FlowType::Device(GOOGLE_DEVICE_CODE_URL.to_string()), &appsecret, "bogus_refresh_token") {
+            RefreshResult::Success(_) => {}
+            _ => panic!("Expected a token"),
+        }
+        assert!(requests.lock().unwrap()[0].contains("client_secret=QeQUnhzsiO4t--ZGmj9muUAu"));
+        assert!(!requests.lock().unwrap()[0].contains(&basic_auth));
+
+        let connector = RecordingConnector::new(&[TOKEN_REPLY]);
+        let requests = connector.requests.clone();
+        let mut flow = RefreshFlow::new(hyper::Client::with_connector(connector))
+            .with_client_auth(ClientAuthMethod::BasicHeader);
+        match *flow.refresh_token(FlowType::Device(GOOGLE_DEVICE_CODE_URL.to_string()), &appsecret, "bogus_refresh_token") {
+            RefreshResult::Success(_) => {}
+            _ => panic!("Expected a token"),
+        }
+        assert!(!requests.lock().unwrap()[0].contains("client_secret="));
+        assert!(requests.lock().unwrap()[0].contains(&basic_auth));
+    }
 }
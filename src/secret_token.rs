@@ -0,0 +1,156 @@
+//! A variant of `Token` keeping its credentials in [`secrecy`](https://docs.rs/secrecy)
+//! wrappers, which clear their memory when dropped.
+//!
+//! Only available with the `secrecy` feature.
+
+use std::fmt;
+
+use chrono::{DateTime, UTC};
+use secrecy::{ExposeSecret, SecretString};
+
+use types::{Token, TokenType, REDACTED};
+
+/// Holds the access, refresh and ID token of a `Token` as `SecretString`s. They are zeroized when
+/// the `SecretToken` is dropped, and never shown by `Debug`; the accessors expose them only
+/// where they are needed, e.g. to authenticate a request.
+///
+/// Turn tokens into `SecretToken`s as soon as they are obtained. The conversion moves the strings
+/// of the `Token` instead of copying them; copies kept elsewhere, e.g. by a `TokenStorage`, are
+/// not affected.
+///
+/// # Examples
+/// ```
+/// # extern crate yup_oauth2 as oauth2;
+/// use oauth2::{SecretToken, Token};
+///
+/// # fn main() {
+/// let token = SecretToken::from(Token::new("1/fFAGRNJru1FTz70BzhT3Zg", "Bearer")
+///     .with_expires_in(3600));
+/// assert_eq!(token.authorization_header_value(), "Bearer 1/fFAGRNJru1FTz70BzhT3Zg");
+/// assert!(!format!("{:?}", token).contains("1/fFAGRNJru1FTz70BzhT3Zg"));
+/// # }
+/// ```
+pub struct SecretToken {
+    access_token: SecretString,
+    refresh_token: SecretString,
+    id_token: Option<SecretString>,
+    /// The token type as string - usually 'Bearer'.
+    pub token_type: String,
+    /// Seconds since epoch at which the token expires, if known.
+    pub expires_in_timestamp: Option<i64>,
+    /// Seconds since epoch at which the token was obtained, if known.
+    pub issued_at_timestamp: Option<i64>,
+    /// The scopes granted by the server, if it said so.
+    pub scopes: Option<Vec<String>>,
+}
+
+impl SecretToken {
+    pub fn access_token(&self) -> &str {
+        self.access_token.expose_secret()
+    }
+
+    /// Empty if the server didn't hand out a refresh token.
+    pub fn refresh_token(&self) -> &str {
+        self.refresh_token.expose_secret()
+    }
+
+    pub fn id_token(&self) -> Option<&str> {
+        self.id_token.as_ref().map(|t| t.expose_secret().as_str())
+    }
+
+    /// Returns the time at which the token expires, or `None` if it doesn't say.
+    pub fn expiration_time(&self) -> Option<DateTime<UTC>> {
+        self.to_expiring_token().expiration_time()
+    }
+
+    /// Returns true if we are expired; see `Token::expired()`.
+    pub fn expired(&self) -> bool {
+        self.to_expiring_token().expired()
+    }
+
+    /// Like `Token::authorization_header_value()`. The returned string contains the access
+    /// token, and is not cleared when dropped.
+    pub fn authorization_header_value(&self) -> String {
+        let token_type = if self.token_type.eq_ignore_ascii_case(TokenType::Bearer.as_ref()) {
+            TokenType::Bearer.as_ref()
+        } else {
+            &self.token_type
+        };
+        format!("{} {}", token_type, self.access_token())
+    }
+
+    // A `Token` carrying nothing but the expiry, to share its logic.
+    fn to_expiring_token(&self) -> Token {
+        let mut token = Token::new(REDACTED, &self.token_type);
+        token.expires_in_timestamp = self.expires_in_timestamp;
+        token
+    }
+}
+
+impl From<Token> for SecretToken {
+    fn from(mut token: Token) -> SecretToken {
+        token.set_expiry_absolute();
+        SecretToken {
+            access_token: SecretString::new(token.access_token),
+            refresh_token: SecretString::new(token.refresh_token),
+            id_token: token.id_token.map(SecretString::new),
+            token_type: token.token_type,
+            expires_in_timestamp: token.expires_in_timestamp,
+            issued_at_timestamp: token.issued_at_timestamp,
+            scopes: token.scopes,
+        }
+    }
+}
+
+impl fmt::Debug for SecretToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        f.debug_struct("SecretToken")
+            .field("access_token", &self.access_token)
+            .field("refresh_token", &self.refresh_token)
+            .field("id_token", &self.id_token)
+            .field("token_type", &self.token_type)
+            .field("expires_in_timestamp", &self.expires_in_timestamp)
+            .field("issued_at_timestamp", &self.issued_at_timestamp)
+            .field("scopes", &self.scopes)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn secret_token() {
+        let token = SecretToken::from(Token::new("1/fFAGRNJru1FTz70BzhT3Zg", "bearer")
+            .with_refresh_token("1/6BMfW9j53gdGImsixUH6kU5RsR4zwI9lUVX-tqf8JXQ")
+            .with_id_token("eyJhbGciOiJSUzI1NiJ9.e30.c2lnbmF0dXJl")
+            .with_expires_in(3600));
+
+        assert_eq!(token.access_token(), "1/fFAGRNJru1FTz70BzhT3Zg");
+        assert_eq!(token.refresh_token(), "1/6BMfW9j53gdGImsixUH6kU5RsR4zwI9lUVX-tqf8JXQ");
+        assert_eq!(token.id_token(), Some("eyJhbGciOiJSUzI1NiJ9.e30.c2lnbmF0dXJl"));
+        assert_eq!(token.authorization_header_value(), "Bearer 1/fFAGRNJru1FTz70BzhT3Zg");
+        assert!(!token.expired());
+
+        let debug = format!("{:?}", token);
+        assert!(debug.contains("REDACTED"));
+        for secret in &["1/fFAGRNJru1FTz70BzhT3Zg",
+                        "1/6BMfW9j53gdGImsixUH6kU5RsR4zwI9lUVX-tqf8JXQ",
+                        "eyJhbGciOiJSUzI1NiJ9"] {
+            assert!(!debug.contains(secret));
+        }
+    }
+
+    #[test]
+    fn secret_token_keeps_the_buffers() {
+        let access_token = "1/fFAGRNJru1FTz70BzhT3Zg".to_string();
+        let address = access_token.as_ptr();
+
+        // Moved rather than copied, so the only copy of the credential is the one zeroized on drop.
+        let mut plain = Token::new("", "Bearer").with_expires_in(60);
+        plain.access_token = access_token;
+        let token = SecretToken::from(plain);
+        assert_eq!(token.access_token().as_ptr(), address);
+    }
+}
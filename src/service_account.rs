@@ -14,13 +14,17 @@
 use std::borrow::BorrowMut;
 use std::default::Default;
 use std::error;
+use std::fmt;
 use std::io::{self, Read};
 use std::result;
 use std::str;
+use std::sync::Arc;
 
 use authenticator::GetToken;
+use clock::{Clock, SystemClock};
+use refresh::token_from_body;
 use storage::{hash_scopes, MemoryStorage, TokenStorage};
-use types::{StringError, Token};
+use types::{Token, DEFAULT_USER_AGENT, REDACTED};
 
 use hyper::header;
 use url::form_urlencoded;
@@ -64,7 +68,9 @@ fn decode_rsa_key(pem_pkcs8: &str) -> Result<PrivateKey, Box<error::Error>> {
 ///
 /// You can use `helpers::service_account_key_from_file()` as a quick way to read a JSON client
 /// secret into a ServiceAccountKey.
-#[derive(Serialize, Deserialize, Debug, Clone)]
+///
+/// The `Debug` output leaves out the private key.
+#[derive(Serialize, Deserialize, Clone)]
 pub struct ServiceAccountKey {
     #[serde(rename="type")]
     pub key_type: Option<String>,
@@ -79,6 +85,23 @@ pub struct ServiceAccountKey {
     pub client_x509_cert_url: Option<String>,
 }
 
+impl fmt::Debug for ServiceAccountKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        f.debug_struct("ServiceAccountKey")
+            .field("key_type", &self.key_type)
+            .field("project_id", &self.project_id)
+            .field("private_key_id", &self.private_key_id)
+            .field("private_key", &self.private_key.as_ref().map(|_| REDACTED))
+            .field("client_email", &self.client_email)
+            .field("client_id", &self.client_id)
+            .field("auth_uri", &self.auth_uri)
+            .field("token_uri", &self.token_uri)
+            .field("auth_provier_x509_cert_url", &self.auth_provier_x509_cert_url)
+            .field("client_x509_cert_url", &self.client_x509_cert_url)
+            .finish()
+    }
+}
+
 #[derive(Serialize, Debug)]
 struct Claims {
     iss: String,
@@ -129,11 +152,11 @@ impl JWT {
     }
 }
 
-fn init_claims_from_key<'a, I, T>(key: &ServiceAccountKey, scopes: I) -> Claims
+fn init_claims_from_key<'a, I, T>(key: &ServiceAccountKey, scopes: I, clock: &Clock) -> Claims
     where T: AsRef<str> + 'a,
           I: IntoIterator<Item = &'a T>
 {
-    let iat = chrono::UTC::now().timestamp();
+    let iat = clock.now().timestamp();
     let expiry = iat + 3600 - 5; // Max validity is 1h.
 
     let mut scopes_string = scopes.into_iter().fold(String::new(), |mut acc, sc| {
@@ -162,33 +185,17 @@ fn set_sub_claim(mut claims: Claims, sub: String) -> Claims {
 
 /// A token source (`GetToken`) yielding OAuth tokens for services that use ServiceAccount authorization.
 /// This token source caches token and automatically renews expired ones.
+///
+/// The token URI of the key is used as is: unlike the other flows, this one doesn't refuse it
+/// if it doesn't use HTTPS, see `Error::InsecureUri`.
 pub struct ServiceAccountAccess<C> {
     client: C,
     key: ServiceAccountKey,
     cache: MemoryStorage,
     sub: Option<String>,
-}
-
-/// This is the schema of the server's response.
-#[derive(Deserialize, Debug)]
-struct TokenResponse {
-    access_token: Option<String>,
-    token_type: Option<String>,
-    expires_in: Option<i64>,
-}
-
-impl TokenResponse {
-    fn to_oauth_token(self) -> Token {
-        let expires_ts = chrono::UTC::now().timestamp() + self.expires_in.unwrap_or(0);
-
-        Token {
-            access_token: self.access_token.unwrap(),
-            token_type: self.token_type.unwrap(),
-            refresh_token: String::new(),
-            expires_in: self.expires_in,
-            expires_in_timestamp: Some(expires_ts),
-        }
-    }
+    user_agent: String,
+    headers: header::Headers,
+    clock: Arc<Clock>,
 }
 
 impl<'a, C> ServiceAccountAccess<C>
@@ -202,6 +209,9 @@ impl<'a, C> ServiceAccountAccess<C>
             key: key,
             cache: MemoryStorage::default(),
             sub: None,
+            user_agent: DEFAULT_USER_AGENT.to_string(),
+            headers: header::Headers::new(),
+            clock: Arc::new(SystemClock),
         }
     }
 
@@ -211,11 +221,35 @@ impl<'a, C> ServiceAccountAccess<C>
             key: key,
             cache: MemoryStorage::default(),
             sub: Some(sub),
+            user_agent: DEFAULT_USER_AGENT.to_string(),
+            headers: header::Headers::new(),
+            clock: Arc::new(SystemClock),
         }
     }
 
+    /// Send `user_agent` as `User-Agent` header, instead of `DEFAULT_USER_AGENT`.
+    pub fn with_user_agent(mut self, user_agent: String) -> ServiceAccountAccess<C> {
+        self.user_agent = user_agent;
+        self
+    }
+
+    /// Add `headers` to every token request, e.g. for a gateway in front of the token endpoint.
+    /// `Content-Type` and `User-Agent` are set by us, whatever `headers` contains.
+    pub fn with_headers(mut self, headers: header::Headers) -> ServiceAccountAccess<C> {
+        self.headers = headers;
+        self
+    }
+
+    /// Ask `clock` for the current time, which dates the signed claims, computes the expiry of
+    /// tokens and decides whether the cached ones are expired. By default, the system time is
+    /// used.
+    pub fn with_clock(mut self, clock: Arc<Clock>) -> ServiceAccountAccess<C> {
+        self.clock = clock;
+        self
+    }
+
     fn request_token(&mut self, scopes: &Vec<&str>) -> result::Result<Token, Box<error::Error>> {
-        let mut claims = init_claims_from_key(&self.key, scopes);
+        let mut claims = init_claims_from_key(&self.key, scopes, &*self.clock);
         claims.sub = self.sub.clone();
         let signed = try!(JWT::new(claims)
             .sign(self.key.private_key.as_ref().unwrap()));
@@ -228,27 +262,14 @@ impl<'a, C> ServiceAccountAccess<C>
         let mut result = try!(self.client
             .borrow_mut()
             .post(self.key.token_uri.as_ref().unwrap())
+            .headers(self.headers.clone())
             .body(&body)
             .header(header::ContentType("application/x-www-form-urlencoded".parse().unwrap()))
+            .header(header::UserAgent(self.user_agent.clone()))
             .send());
 
         try!(result.read_to_string(&mut response));
-
-        let token: Result<TokenResponse, serde_json::error::Error> =
-            serde_json::from_str(&response);
-
-        match token {
-            Err(e) => return Err(Box::new(e)),
-            Ok(token) => {
-                if token.access_token.is_none() || token.token_type.is_none() ||
-                   token.expires_in.is_none() {
-                    Err(Box::new(StringError::new("Token response lacks fields".to_string(),
-                                                  Some(&format!("{:?}", token)))))
-                } else {
-                    Ok(token.to_oauth_token())
-                }
-            }
-        }
+        Ok(try!(token_from_body(&response, &*self.clock)))
     }
 }
 
@@ -260,7 +281,7 @@ impl<C: BorrowMut<hyper::Client>> GetToken for ServiceAccountAccess<C> {
         let (hash, scps) = hash_scopes(scopes);
 
         if let Some(token) = try!(self.cache.get(hash, &scps)) {
-            if !token.expired() {
+            if !token.expired_with_clock(chrono::Duration::zero(), &*self.clock) {
                 return Ok(token);
             }
         }
@@ -279,10 +300,7 @@ impl<C: BorrowMut<hyper::Client>> GetToken for ServiceAccountAccess<C> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use helper::service_account_key_from_file;
-    use hyper;
-    use hyper::net::HttpsConnector;
-    use hyper_rustls;
+    use helper::{client_from_env, service_account_key_from_file};
     use authenticator::GetToken;
 
     // This is a valid but deactivated key.
@@ -293,17 +311,25 @@ mod tests {
     #[allow(dead_code)]
     fn test_service_account_e2e() {
         let key = service_account_key_from_file(&TEST_PRIVATE_KEY_PATH.to_string()).unwrap();
-        let client = hyper::Client::with_connector(HttpsConnector::new(hyper_rustls::TlsClient::new()));
-        let mut acc = ServiceAccountAccess::new(key, client);
+        let mut acc = ServiceAccountAccess::new(key, client_from_env().unwrap());
         println!("{:?}",
                  acc.token(vec![&"https://www.googleapis.com/auth/pubsub"]).unwrap());
     }
 
+    #[test]
+    fn test_key_debug_is_redacted() {
+        let key = service_account_key_from_file(&TEST_PRIVATE_KEY_PATH.to_string()).unwrap();
+        let debug = format!("{:?}", key);
+        assert!(!debug.contains("PRIVATE KEY"));
+        assert!(debug.contains("private_key: Some(\"<redacted>\")"));
+        assert!(debug.contains("oauth2-public-test@sanguine-rhythm-105020.iam.gserviceaccount.com"));
+    }
+
     #[test]
     fn test_jwt_initialize_claims() {
         let key = service_account_key_from_file(&TEST_PRIVATE_KEY_PATH.to_string()).unwrap();
         let scopes = vec!["scope1", "scope2", "scope3"];
-        let claims = super::init_claims_from_key(&key, &scopes);
+        let claims = super::init_claims_from_key(&key, &scopes, &SystemClock);
 
         assert_eq!(claims.iss,
                    "oauth2-public-test@sanguine-rhythm-105020.iam.gserviceaccount.com".to_string());
@@ -319,7 +345,7 @@ mod tests {
     fn test_jwt_sign() {
         let key = service_account_key_from_file(&TEST_PRIVATE_KEY_PATH.to_string()).unwrap();
         let scopes = vec!["scope1", "scope2", "scope3"];
-        let claims = super::init_claims_from_key(&key, &scopes);
+        let claims = super::init_claims_from_key(&key, &scopes, &SystemClock);
         let jwt = super::JWT::new(claims);
         let signature = jwt.sign(key.private_key.as_ref().unwrap());
 
@@ -329,4 +355,73 @@ mod tests {
         assert_eq!(signature.split(".").nth(0).unwrap(),
                    "eyJhbGciOiJSUzI1NiIsInR5cCI6IkpXVCJ9");
     }
+
+    #[test]
+    fn test_service_account_token() {
+        use refresh::tests::{RecordingConnector, TOKEN_REPLY};
+
+        let key = service_account_key_from_file(&TEST_PRIVATE_KEY_PATH.to_string()).unwrap();
+        let connector = RecordingConnector::new(&[TOKEN_REPLY]);
+        let requests = connector.requests.clone();
+        let mut acc = ServiceAccountAccess::new(key, hyper::Client::with_connector(connector));
+
+        let token = acc.token(vec![&"https://www.googleapis.com/auth/pubsub"]).unwrap();
+        assert_eq!(token.access_token, "1/fFAGRNJru1FTz70BzhT3Zg");
+        assert!(!token.expired());
+
+        let request = requests.lock().unwrap()[0].clone();
+        assert!(request.contains("grant_type=urn%3Aietf%3Aparams%3Aoauth%3Agrant-type%3Ajwt-bearer"));
+        assert!(request.contains("assertion=eyJhbGciOiJSUzI1NiIsInR5cCI6IkpXVCJ9."));
+        assert!(request.contains("User-Agent: yup-oauth2/"));
+
+        // The token is cached, so the mock is not asked again.
+        acc.token(vec![&"https://www.googleapis.com/auth/pubsub"]).unwrap();
+        assert_eq!(requests.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_service_account_token_errors() {
+        use refresh::tests::RecordingConnector;
+
+        let key = service_account_key_from_file(&TEST_PRIVATE_KEY_PATH.to_string()).unwrap();
+        let connector = RecordingConnector::new(&["HTTP/1.1 400 Bad Request\r\n\
+                                                   \r\n\
+                                                   {\"error\":\"invalid_grant\",\
+                                                   \"error_description\":\"Invalid JWT Signature.\"}",
+                                                  "HTTP/1.1 200 OK\r\n\
+                                                   \r\n\
+                                                   {\"token_type\":\"Bearer\",\"expires_in\":3600}"]);
+        let mut acc = ServiceAccountAccess::new(key, hyper::Client::with_connector(connector));
+
+        let err = acc.token(vec![&"https://www.googleapis.com/auth/pubsub"]).unwrap_err();
+        assert_eq!(err.to_string(), "invalid_grant: Invalid JWT Signature.");
+        let err = acc.token(vec![&"https://www.googleapis.com/auth/pubsub"]).unwrap_err();
+        assert!(err.to_string()
+            .starts_with("Invalid server response: missing field `access_token`"));
+    }
+
+    #[test]
+    fn test_service_account_token_with_clock() {
+        use clock::MockClock;
+        use refresh::tests::{RecordingConnector, TOKEN_REPLY};
+
+        let key = service_account_key_from_file(&TEST_PRIVATE_KEY_PATH.to_string()).unwrap();
+        let connector = RecordingConnector::new(&[TOKEN_REPLY, TOKEN_REPLY]);
+        let requests = connector.requests.clone();
+        let clock = Arc::new(MockClock::new(chrono::UTC::now() - chrono::Duration::days(1)));
+        let mut acc = ServiceAccountAccess::new(key, hyper::Client::with_connector(connector))
+            .with_clock(clock.clone());
+
+        let token = acc.token(vec![&"https://www.googleapis.com/auth/pubsub"]).unwrap();
+        assert_eq!(token.issued_at_timestamp, Some(clock.now().timestamp()));
+        assert!(token.expired());
+        assert!(!token.expired_with_clock(chrono::Duration::zero(), &*clock));
+
+        // Only the clock decides whether the cached token is still good.
+        acc.token(vec![&"https://www.googleapis.com/auth/pubsub"]).unwrap();
+        assert_eq!(requests.lock().unwrap().len(), 1);
+        clock.advance(chrono::Duration::hours(2));
+        acc.token(vec![&"https://www.googleapis.com/auth/pubsub"]).unwrap();
+        assert_eq!(requests.lock().unwrap().len(), 2);
+    }
 }
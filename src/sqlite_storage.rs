@@ -0,0 +1,209 @@
+//! A `TokenStorage` keeping tokens in an SQLite database, which may be shared by several
+//! processes.
+//!
+//! Only available with the `rusqlite` feature.
+
+use std::error::Error;
+use std::fmt;
+use std::time::Duration;
+
+use chrono::UTC;
+use rusqlite::{self, Connection, TransactionBehavior};
+use rusqlite::types::ToSql;
+use serde_json;
+
+use storage::TokenStorage;
+use types::Token;
+
+// How long to wait for another process to finish writing before giving up.
+const BUSY_TIMEOUT_SECS: u64 = 5;
+
+/// Stores tokens in the table `tokens` of an SQLite database, as rows of
+/// `(scope_hash, token_json, updated_at)`.
+///
+/// The database is switched to [WAL mode](https://www.sqlite.org/wal.html), so that any number of
+/// processes may read and write it at the same time. Each worker of a service should open its own
+/// `SqliteTokenStorage` on the same file; a token refreshed by one of them is then seen by all.
+pub struct SqliteTokenStorage {
+    conn: Connection,
+}
+
+impl SqliteTokenStorage {
+    /// Opens the database at `path`, creating it and the `tokens` table if needed.
+    pub fn new(path: &str) -> Result<SqliteTokenStorage, SqliteStorageError> {
+        SqliteTokenStorage::with_connection(try!(Connection::open(path)))
+    }
+
+    /// Uses an already opened connection, e.g. to a database shared with other data of the
+    /// application. The connection is set up as by `new()`.
+    pub fn with_connection(conn: Connection) -> Result<SqliteTokenStorage, SqliteStorageError> {
+        try!(conn.busy_timeout(Duration::from_secs(BUSY_TIMEOUT_SECS)));
+        // The pragma answers with the resulting mode, so it has to be run as a query.
+        let mode: String =
+            try!(conn.query_row("PRAGMA journal_mode=WAL", &[], |row| row.get(0)));
+        if mode.to_lowercase() != "wal" {
+            // In-memory databases can't use WAL, but aren't shared with other processes either.
+            debug!("SQLite token storage uses journal mode {}", mode);
+        }
+        try!(conn.execute_batch("CREATE TABLE IF NOT EXISTS tokens (
+                                     scope_hash INTEGER PRIMARY KEY,
+                                     token_json TEXT NOT NULL,
+                                     updated_at INTEGER NOT NULL
+                                 );"));
+        Ok(SqliteTokenStorage { conn: conn })
+    }
+}
+
+/// Failures of a `SqliteTokenStorage`.
+#[derive(Debug)]
+pub enum SqliteStorageError {
+    /// The database could not be opened, read or written
+    Sqlite(rusqlite::Error),
+    /// A stored token could not be parsed
+    Json(serde_json::Error),
+}
+
+impl From<rusqlite::Error> for SqliteStorageError {
+    fn from(value: rusqlite::Error) -> SqliteStorageError {
+        SqliteStorageError::Sqlite(value)
+    }
+}
+
+impl From<serde_json::Error> for SqliteStorageError {
+    fn from(value: serde_json::Error) -> SqliteStorageError {
+        SqliteStorageError::Json(value)
+    }
+}
+
+impl fmt::Display for SqliteStorageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            SqliteStorageError::Sqlite(ref err) => write!(f, "SQLite error: {}", err),
+            SqliteStorageError::Json(ref err) => write!(f, "Invalid stored token: {}", err),
+        }
+    }
+}
+
+impl Error for SqliteStorageError {
+    fn description(&self) -> &str {
+        match *self {
+            SqliteStorageError::Sqlite(_) => "sqlite error",
+            SqliteStorageError::Json(_) => "invalid stored token",
+        }
+    }
+}
+
+impl TokenStorage for SqliteTokenStorage {
+    type Error = SqliteStorageError;
+
+    fn set(&mut self,
+           scope_hash: u64,
+           _: &Vec<&str>,
+           token: Option<Token>)
+           -> Result<(), SqliteStorageError> {
+        // SQLite only knows signed integers; the cast keeps all bits.
+        let key = scope_hash as i64;
+        // Take the write lock right away, rather than failing to upgrade a read lock when another
+        // process writes at the same time.
+        let tx = try!(self.conn.transaction_with_behavior(TransactionBehavior::Immediate));
+        match token {
+            Some(t) => {
+                let json = try!(serde_json::to_string(&t));
+                try!(tx.execute("INSERT OR REPLACE INTO tokens (scope_hash, token_json, \
+                                 updated_at) VALUES (?, ?, ?)",
+                                &[&key as &ToSql, &json, &UTC::now().timestamp()]));
+            }
+            None => {
+                try!(tx.execute("DELETE FROM tokens WHERE scope_hash = ?", &[&key]));
+            }
+        }
+        Ok(try!(tx.commit()))
+    }
+
+    fn get(&self, scope_hash: u64, _: &Vec<&str>) -> Result<Option<Token>, SqliteStorageError> {
+        let key = scope_hash as i64;
+        let json: String = match self.conn.query_row("SELECT token_json FROM tokens WHERE \
+                                                      scope_hash = ?",
+                                                     &[&key],
+                                                     |row| row.get(0)) {
+            Ok(json) => json,
+            Err(rusqlite::Error::QueryReturnedNoRows) => return Ok(None),
+            Err(err) => return Err(SqliteStorageError::from(err)),
+        };
+        Ok(Some(try!(serde_json::from_str(&json))))
+    }
+
+    fn keys(&self) -> Result<Vec<u64>, SqliteStorageError> {
+        let mut stmt = try!(self.conn.prepare("SELECT scope_hash FROM tokens"));
+        let rows = try!(stmt.query_map(&[], |row| row.get::<_, i64>(0)));
+        let mut keys = Vec::new();
+        for key in rows {
+            keys.push(try!(key) as u64);
+        }
+        // The database orders the keys as signed integers.
+        keys.sort();
+        Ok(keys)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    fn test_token(access_token: &str) -> Token {
+        Token::new(access_token, "Bearer")
+            .with_refresh_token("1/6BMfW9j53gdGImsixUH6kU5RsR4zwI9lUVX-tqf8JXQ")
+            .with_expiry_timestamp(1500000000)
+    }
+
+    fn temp_location(name: &str) -> String {
+        let mut path = env::temp_dir();
+        path.push(format!("yup-oauth2-{}-{}.sqlite", name, process::id()));
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn sqlite_storage_shared_between_connections() {
+        let location = temp_location("shared");
+        let _ = fs::remove_file(&location);
+        let scopes = vec!["https://www.googleapis.com/auth/drive"];
+
+        let mut first = SqliteTokenStorage::new(&location).unwrap();
+        let mut second = SqliteTokenStorage::new(&location).unwrap();
+        assert_eq!(first.get(42, &scopes).unwrap(), None);
+
+        first.set(42, &scopes, Some(test_token("first"))).unwrap();
+        assert_eq!(second.get(42, &scopes).unwrap(), Some(test_token("first")));
+
+        // The latest write wins, whichever connection made it.
+        second.set(42, &scopes, Some(test_token("second"))).unwrap();
+        assert_eq!(first.get(42, &scopes).unwrap(), Some(test_token("second")));
+        assert_eq!(first.get(43, &scopes).unwrap(), None);
+
+        first.set(42, &scopes, None).unwrap();
+        assert_eq!(second.get(42, &scopes).unwrap(), None);
+
+        drop(first);
+        drop(second);
+        let _ = fs::remove_file(&location);
+        let _ = fs::remove_file(format!("{}-wal", location));
+        let _ = fs::remove_file(format!("{}-shm", location));
+    }
+
+    #[test]
+    fn sqlite_storage_keeps_hashes_with_high_bit() {
+        let mut storage = SqliteTokenStorage::with_connection(Connection::open_in_memory()
+                .unwrap())
+            .unwrap();
+        let scopes = vec!["https://www.googleapis.com/auth/drive"];
+        storage.set(u64::max_value(), &scopes, Some(test_token("high"))).unwrap();
+        assert_eq!(storage.get(u64::max_value(), &scopes).unwrap(),
+                   Some(test_token("high")));
+
+        storage.set(42, &scopes, Some(test_token("low"))).unwrap();
+        assert_eq!(storage.keys().unwrap(), vec![42, u64::max_value()]);
+    }
+}
@@ -6,13 +6,15 @@
 extern crate serde_json;
 
 use std::collections::HashMap;
-use std::collections::hash_map::DefaultHasher;
 use std::error::Error;
 use std::fmt;
 use std::fs;
-use std::hash::{Hash, Hasher};
 use std::io::{Read, Write};
 use std::io;
+use std::iter;
+
+use ring::aead;
+use ring::rand::{SecureRandom, SystemRandom};
 
 use types::Token;
 
@@ -33,21 +35,100 @@ pub trait TokenStorage {
            -> Result<(), Self::Error>;
     /// A `None` result indicates that there is no token for the given scope_hash.
     fn get(&self, scope_hash: u64, scopes: &Vec<&str>) -> Result<Option<Token>, Self::Error>;
+
+    /// Returns any stored token granted at least `scopes`, along with the scope hash it is
+    /// stored under, which is asked for when `get()` found no token for exactly these scopes.
+    /// Storages which can't list their tokens may keep the default, which finds none.
+    fn get_covering(&self, _scopes: &Vec<&str>) -> Result<Option<(u64, Token)>, Self::Error> {
+        Ok(None)
+    }
+
+    /// Returns the scope hashes of all stored tokens, in ascending order, e.g. to remove them
+    /// with `set(hash, &vec![], None)`. Storages which can't list their tokens may keep the
+    /// default, which returns none.
+    fn keys(&self) -> Result<Vec<u64>, Self::Error> {
+        Ok(Vec::new())
+    }
+}
+
+fn sorted_keys(tokens: &HashMap<u64, Token>) -> Vec<u64> {
+    let mut keys = tokens.keys().cloned().collect::<Vec<u64>>();
+    keys.sort();
+    keys
+}
+
+// Of the tokens covering `scopes`, the one expiring last, with its key.
+fn find_covering(tokens: &HashMap<u64, Token>, scopes: &Vec<&str>) -> Option<(u64, Token)> {
+    tokens.iter()
+        .filter(|&(_, t)| t.covers_scopes(scopes))
+        .max_by_key(|&(_, t)| t.expires_in_timestamp)
+        .map(|(key, t)| (*key, t.clone()))
 }
 
 /// Calculate a hash value describing the scopes, and return a sorted Vec of the scopes.
+///
+/// The order of the scopes, as well as duplicates, don't matter. The hash is the 64 bit FNV-1a
+/// hash of the sorted, deduplicated scopes joined by single spaces. It doesn't depend on the
+/// process, build or platform, which allows using it as key of persistent storage, and to look up
+/// tokens yourself.
 pub fn hash_scopes<'a, I, T>(scopes: I) -> (u64, Vec<&'a str>)
     where T: AsRef<str> + Ord + 'a,
           I: IntoIterator<Item = &'a T>
+{
+    hash_scopes_for_account("", scopes)
+}
+
+/// Like `hash_scopes()`, but the hash also depends on `account_id`, e.g. the email address of a
+/// user, so that the tokens of several accounts can be kept in the same storage. The empty
+/// account has the same hashes as `hash_scopes()`; see `migrate_to_account()`.
+pub fn hash_scopes_for_account<'a, I, T>(account_id: &str, scopes: I) -> (u64, Vec<&'a str>)
+    where T: AsRef<str> + Ord + 'a,
+          I: IntoIterator<Item = &'a T>
 {
     let mut sv: Vec<&str> = scopes.into_iter()
         .map(|s| s.as_ref())
         .collect::<Vec<&str>>();
     sv.sort();
-    let mut sh = DefaultHasher::new();
-    &sv.hash(&mut sh);
-    let sv = sv;
-    (sh.finish(), sv)
+    sv.dedup();
+
+    let mut hash: u64 = 0xcbf29ce484222325;
+    let mut feed = |bytes: &[u8]| for b in bytes {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    };
+    if !account_id.is_empty() {
+        // NUL can't be part of a scope, so no account and scopes hash like other scopes.
+        feed(account_id.as_bytes());
+        feed(b"\0");
+    }
+    for (i, scope) in sv.iter().enumerate() {
+        if i > 0 {
+            feed(b" ");
+        }
+        feed(scope.as_bytes());
+    }
+    (hash, sv)
+}
+
+/// Moves the token stored for `scopes` by an application which didn't distinguish accounts to
+/// where an `Authenticator` set up `with_account(account_id)` looks for it. Returns whether there
+/// was a token to move.
+pub fn migrate_to_account<S>(storage: &mut S,
+                             account_id: &str,
+                             scopes: &[&str])
+                             -> Result<bool, S::Error>
+    where S: TokenStorage
+{
+    let (old_key, sorted_scopes) = hash_scopes(scopes);
+    let (new_key, _) = hash_scopes_for_account(account_id, scopes);
+    match try!(storage.get(old_key, &sorted_scopes)) {
+        Some(token) => {
+            try!(storage.set(new_key, &sorted_scopes, Some(token)));
+            try!(storage.set(old_key, &sorted_scopes, None));
+            Ok(true)
+        }
+        None => Ok(false),
+    }
 }
 
 /// A storage that remembers nothing.
@@ -84,6 +165,31 @@ pub struct MemoryStorage {
     pub tokens: HashMap<u64, Token>,
 }
 
+impl MemoryStorage {
+    /// Forgets all stored tokens.
+    pub fn clear(&mut self) {
+        self.tokens.clear();
+    }
+
+    /// Serializes all stored tokens, along with the scope hashes they are stored under, to a
+    /// JSON string, e.g. to keep them in the configuration of an application. The format is the
+    /// one of `DiskTokenStorage` files.
+    pub fn export(&self) -> String {
+        // Tokens consist of strings and numbers only, which always serialize.
+        tokens_to_json(&self.tokens).unwrap()
+    }
+
+    /// Adds the tokens of a string returned by `export()`, replacing those stored under the same
+    /// scope hashes. All tokens are kept, whether or not their hashes belong to scopes known to
+    /// this application. Fails with `InvalidData` if `exported` can't be parsed, in which case
+    /// nothing is changed.
+    pub fn import(&mut self, exported: &str) -> Result<(), io::Error> {
+        let tokens = try!(tokens_from_json(exported.as_bytes()));
+        self.tokens.extend(tokens);
+        Ok(())
+    }
+}
+
 impl TokenStorage for MemoryStorage {
     type Error = NullError;
 
@@ -105,6 +211,14 @@ impl TokenStorage for MemoryStorage {
             None => Ok(None),
         }
     }
+
+    fn get_covering(&self, scopes: &Vec<&str>) -> Result<Option<(u64, Token)>, NullError> {
+        Ok(find_covering(&self.tokens, scopes))
+    }
+
+    fn keys(&self) -> Result<Vec<u64>, NullError> {
+        Ok(sorted_keys(&self.tokens))
+    }
 }
 
 /// A single stored token.
@@ -121,6 +235,9 @@ struct JSONTokens {
 }
 
 /// Serializes tokens to a JSON file on disk.
+///
+/// The file is rewritten on every `set()`. A missing file is created, and a corrupt one is
+/// treated as if it was empty.
 #[derive(Default)]
 pub struct DiskTokenStorage {
     location: String,
@@ -141,7 +258,8 @@ impl DiskTokenStorage {
             Result::Ok(()) => Result::Ok(dts),
             Result::Err(e) => {
                 match e.kind() {
-                    io::ErrorKind::NotFound => Result::Ok(dts), // File not found; ignore and create new one
+                    io::ErrorKind::NotFound => dts.dump_to_file().map(|_| dts), // File not found; create new one
+                    io::ErrorKind::InvalidData => Result::Ok(dts), // Corrupt file; overwritten by next set()
                     _ => Result::Err(e), // e.g. PermissionDenied
                 }
             }
@@ -150,50 +268,57 @@ impl DiskTokenStorage {
 
     fn load_from_file(&mut self) -> Result<(), io::Error> {
         let mut f = try!(fs::OpenOptions::new().read(true).open(&self.location));
-        let mut contents = String::new();
+        let mut contents = Vec::new();
+        try!(f.read_to_end(&mut contents));
 
-        match f.read_to_string(&mut contents) {
-            Result::Err(e) => return Result::Err(e),
-            Result::Ok(_sz) => (),
-        }
+        self.tokens = try!(tokens_from_json(&contents));
+        return Result::Ok(());
+    }
 
-        let tokens: JSONTokens;
+    pub fn dump_to_file(&mut self) -> Result<(), io::Error> {
+        let serialized = try!(tokens_to_json(&self.tokens));
+        write_atomically(&self.location, serialized.as_ref())
+    }
+}
 
-        match serde_json::from_str(&contents) {
-            Result::Err(e) => return Result::Err(io::Error::new(io::ErrorKind::InvalidData, e)),
-            Result::Ok(t) => tokens = t,
-        }
+fn tokens_from_json(contents: &[u8]) -> Result<HashMap<u64, Token>, io::Error> {
+    let tokens: JSONTokens;
 
-        for t in tokens.tokens {
-            self.tokens.insert(t.hash, t.token);
-        }
-        return Result::Ok(());
+    match serde_json::from_slice(contents) {
+        Result::Err(e) => return Result::Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+        Result::Ok(t) => tokens = t,
     }
 
-    pub fn dump_to_file(&mut self) -> Result<(), io::Error> {
-        let mut jsontokens = JSONTokens { tokens: Vec::new() };
+    Result::Ok(tokens.tokens.into_iter().map(|t| (t.hash, t.token)).collect())
+}
 
-        for (hash, token) in self.tokens.iter() {
-            jsontokens.tokens.push(JSONToken {
-                hash: *hash,
-                token: token.clone(),
-            });
-        }
+fn tokens_to_json(tokens: &HashMap<u64, Token>) -> Result<String, io::Error> {
+    let mut jsontokens = JSONTokens { tokens: Vec::new() };
 
-        let serialized;;
+    for (hash, token) in tokens.iter() {
+        jsontokens.tokens.push(JSONToken {
+            hash: *hash,
+            token: token.clone(),
+        });
+    }
 
-        match serde_json::to_string(&jsontokens) {
-            Result::Err(e) => return Result::Err(io::Error::new(io::ErrorKind::InvalidData, e)),
-            Result::Ok(s) => serialized = s,
-        }
+    serde_json::to_string(&jsontokens).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
 
+// Write to a temporary file which then replaces the old one, so that a crash can't
+// leave a partially written file behind.
+fn write_atomically(location: &str, contents: &[u8]) -> Result<(), io::Error> {
+    let tmp_location = format!("{}.tmp", location);
+    {
         let mut f = try!(fs::OpenOptions::new()
             .create(true)
             .write(true)
             .truncate(true)
-            .open(&self.location));
-        f.write(serialized.as_ref()).map(|_| ())
+            .open(&tmp_location));
+        try!(f.write_all(contents));
+        try!(f.sync_all());
     }
+    fs::rename(&tmp_location, location)
 }
 
 impl TokenStorage for DiskTokenStorage {
@@ -218,4 +343,354 @@ impl TokenStorage for DiskTokenStorage {
     fn get(&self, scope_hash: u64, _: &Vec<&str>) -> Result<Option<Token>, Self::Error> {
         Result::Ok(self.tokens.get(&scope_hash).map(|tok| tok.clone()))
     }
+    fn get_covering(&self, scopes: &Vec<&str>) -> Result<Option<(u64, Token)>, Self::Error> {
+        Ok(find_covering(&self.tokens, scopes))
+    }
+
+    fn keys(&self) -> Result<Vec<u64>, Self::Error> {
+        Ok(sorted_keys(&self.tokens))
+    }
+}
+
+/// Like `DiskTokenStorage`, but encrypts the file with AES-256-GCM, using a key supplied by the
+/// application. Use it where tokens must not be stored in plaintext, but no OS keyring is
+/// available.
+///
+/// Every write uses a fresh random nonce, which is stored in front of the ciphertext.
+pub struct EncryptedDiskTokenStorage {
+    location: String,
+    key: Vec<u8>,
+    tokens: HashMap<u64, Token>,
+}
+
+// Authenticated along with the ciphertext, so that other files encrypted with the same key
+// aren't mistaken for a token store.
+const ENCRYPTED_STORAGE_AD: &'static [u8] = b"yup-oauth2 encrypted token storage v1";
+
+impl EncryptedDiskTokenStorage {
+    /// Opens the storage at `location`, creating it if it doesn't exist. `key` must be 32 bytes
+    /// long.
+    ///
+    /// Unlike with `DiskTokenStorage`, a file that can't be decrypted, e.g. because `key` is
+    /// wrong, is not overwritten; an error of kind `InvalidData` is returned instead.
+    pub fn new(location: &String, key: &[u8]) -> Result<EncryptedDiskTokenStorage, io::Error> {
+        if key.len() != aead::AES_256_GCM.key_len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "Token storage key must be 32 bytes long"));
+        }
+        let mut storage = EncryptedDiskTokenStorage {
+            location: location.clone(),
+            key: key.to_vec(),
+            tokens: HashMap::new(),
+        };
+
+        match storage.load_from_file() {
+            Ok(()) => Ok(storage),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                storage.dump_to_file().map(|_| storage)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn load_from_file(&mut self) -> Result<(), io::Error> {
+        let mut f = try!(fs::OpenOptions::new().read(true).open(&self.location));
+        let mut contents = Vec::new();
+        try!(f.read_to_end(&mut contents));
+
+        let nonce_len = aead::AES_256_GCM.nonce_len();
+        if contents.len() < nonce_len {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Token storage is truncated"));
+        }
+        let (nonce, ciphertext) = contents.split_at_mut(nonce_len);
+        let key = aead::OpeningKey::new(&aead::AES_256_GCM, &self.key).unwrap();
+        let plaintext = try!(aead::open_in_place(&key, nonce, ENCRYPTED_STORAGE_AD, 0, ciphertext)
+            .map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidData,
+                               "Could not decrypt token storage; is the key correct?")
+            }));
+
+        self.tokens = try!(tokens_from_json(plaintext));
+        Ok(())
+    }
+
+    pub fn dump_to_file(&mut self) -> Result<(), io::Error> {
+        let nonce_len = aead::AES_256_GCM.nonce_len();
+        let tag_len = aead::AES_256_GCM.tag_len();
+
+        let mut contents = vec![0u8; nonce_len];
+        try!(SystemRandom::new()
+            .fill(&mut contents)
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "Could not generate a nonce")));
+        contents.extend_from_slice(try!(tokens_to_json(&self.tokens)).as_bytes());
+        contents.extend(iter::repeat(0).take(tag_len));
+
+        let key = aead::SealingKey::new(&aead::AES_256_GCM, &self.key).unwrap();
+        {
+            let (nonce, in_out) = contents.split_at_mut(nonce_len);
+            try!(aead::seal_in_place(&key, nonce, ENCRYPTED_STORAGE_AD, in_out, tag_len)
+                .map_err(|_| io::Error::new(io::ErrorKind::Other, "Could not encrypt tokens")));
+        }
+        write_atomically(&self.location, &contents)
+    }
+}
+
+impl TokenStorage for EncryptedDiskTokenStorage {
+    type Error = io::Error;
+
+    fn set(&mut self,
+           scope_hash: u64,
+           _: &Vec<&str>,
+           token: Option<Token>)
+           -> Result<(), io::Error> {
+        match token {
+            None => self.tokens.remove(&scope_hash),
+            Some(t) => self.tokens.insert(scope_hash, t),
+        };
+        self.dump_to_file()
+    }
+
+    fn get(&self, scope_hash: u64, _: &Vec<&str>) -> Result<Option<Token>, io::Error> {
+        Ok(self.tokens.get(&scope_hash).cloned())
+    }
+
+    fn get_covering(&self, scopes: &Vec<&str>) -> Result<Option<(u64, Token)>, io::Error> {
+        Ok(find_covering(&self.tokens, scopes))
+    }
+
+    fn keys(&self) -> Result<Vec<u64>, io::Error> {
+        Ok(sorted_keys(&self.tokens))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    fn test_token(access_token: &str) -> Token {
+        Token::new(access_token, "Bearer")
+            .with_refresh_token("1/6BMfW9j53gdGImsixUH6kU5RsR4zwI9lUVX-tqf8JXQ")
+            .with_expiry_timestamp(1500000000)
+    }
+
+    fn temp_location(name: &str) -> String {
+        let mut path = env::temp_dir();
+        path.push(format!("yup-oauth2-{}-{}.json", name, process::id()));
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn disk_storage_persists_tokens() {
+        let location = temp_location("persist");
+        let _ = fs::remove_file(&location);
+        let (hash, scopes) = hash_scopes(&["https://www.googleapis.com/auth/youtube.upload"]);
+
+        {
+            let mut storage = DiskTokenStorage::new(&location).unwrap();
+            assert!(fs::metadata(&location).is_ok());
+            storage.set(hash, &scopes, Some(test_token("1/fFAGRNJru1FTz70BzhT3Zg"))).unwrap();
+        }
+
+        let storage = DiskTokenStorage::new(&location).unwrap();
+        assert_eq!(storage.get(hash, &scopes).unwrap(),
+                   Some(test_token("1/fFAGRNJru1FTz70BzhT3Zg")));
+        fs::remove_file(&location).unwrap();
+    }
+
+    #[test]
+    fn storages_list_keys() {
+        let location = temp_location("keys");
+        let _ = fs::remove_file(&location);
+        let mut disk = DiskTokenStorage::new(&location).unwrap();
+        let mut memory = MemoryStorage::default();
+        let mut hashes = Vec::new();
+        for scope in &[["https://www.googleapis.com/auth/youtube.upload"],
+                       ["https://www.googleapis.com/auth/pubsub"],
+                       ["https://www.googleapis.com/auth/drive"]] {
+            let (hash, scopes) = hash_scopes(scope);
+            disk.set(hash, &scopes, Some(test_token(scope[0]))).unwrap();
+            memory.set(hash, &scopes, Some(test_token(scope[0]))).unwrap();
+            hashes.push(hash);
+        }
+        hashes.sort();
+
+        assert_eq!(memory.keys().unwrap(), hashes);
+        assert_eq!(DiskTokenStorage::new(&location).unwrap().keys().unwrap(), hashes);
+        assert!(NullStorage.keys().unwrap().is_empty());
+
+        // Evicting a listed token needs nothing but its key.
+        disk.set(hashes[0], &vec![], None).unwrap();
+        assert_eq!(DiskTokenStorage::new(&location).unwrap().keys().unwrap(), &hashes[1..]);
+        fs::remove_file(&location).unwrap();
+    }
+
+    #[test]
+    fn disk_storage_ignores_corrupt_file() {
+        let location = temp_location("corrupt");
+        fs::File::create(&location).unwrap().write_all(b"{\"tokens\":[{\"ha").unwrap();
+        let (hash, scopes) = hash_scopes(&["https://www.googleapis.com/auth/youtube.upload"]);
+
+        let mut storage = DiskTokenStorage::new(&location).unwrap();
+        assert_eq!(storage.get(hash, &scopes).unwrap(), None);
+        storage.set(hash, &scopes, Some(test_token("1/fFAGRNJru1FTz70BzhT3Zg"))).unwrap();
+
+        let storage = DiskTokenStorage::new(&location).unwrap();
+        assert!(storage.get(hash, &scopes).unwrap().is_some());
+        fs::remove_file(&location).unwrap();
+    }
+
+    #[test]
+    fn encrypted_disk_storage_roundtrip() {
+        let location = temp_location("encrypted");
+        let _ = fs::remove_file(&location);
+        let key = [7u8; 32];
+        let (hash, scopes) = hash_scopes(&["https://www.googleapis.com/auth/youtube.upload"]);
+
+        {
+            let mut storage = EncryptedDiskTokenStorage::new(&location, &key).unwrap();
+            storage.set(hash, &scopes, Some(test_token("1/fFAGRNJru1FTz70BzhT3Zg"))).unwrap();
+        }
+        let mut contents = Vec::new();
+        fs::File::open(&location).unwrap().read_to_end(&mut contents).unwrap();
+        assert!(!String::from_utf8_lossy(&contents).contains("fFAGRNJru1FTz70BzhT3Zg"));
+
+        let mut storage = EncryptedDiskTokenStorage::new(&location, &key).unwrap();
+        assert_eq!(storage.get(hash, &scopes).unwrap(),
+                   Some(test_token("1/fFAGRNJru1FTz70BzhT3Zg")));
+
+        // The nonce changes with every write.
+        storage.set(hash, &scopes, Some(test_token("1/fFAGRNJru1FTz70BzhT3Zg"))).unwrap();
+        let mut rewritten = Vec::new();
+        fs::File::open(&location).unwrap().read_to_end(&mut rewritten).unwrap();
+        assert!(contents[..12] != rewritten[..12]);
+        fs::remove_file(&location).unwrap();
+    }
+
+    #[test]
+    fn encrypted_disk_storage_wrong_key() {
+        let location = temp_location("wrong-key");
+        let _ = fs::remove_file(&location);
+        let (hash, scopes) = hash_scopes(&["https://www.googleapis.com/auth/youtube.upload"]);
+
+        EncryptedDiskTokenStorage::new(&location, &[7u8; 32])
+            .unwrap()
+            .set(hash, &scopes, Some(test_token("1/fFAGRNJru1FTz70BzhT3Zg")))
+            .unwrap();
+
+        match EncryptedDiskTokenStorage::new(&location, &[8u8; 32]) {
+            Err(ref e) if e.kind() == io::ErrorKind::InvalidData => {}
+            Err(e) => panic!("Expected InvalidData, got {:?}", e),
+            Ok(_) => panic!("Decrypted with the wrong key"),
+        }
+        match EncryptedDiskTokenStorage::new(&location, &[7u8; 16]) {
+            Err(ref e) if e.kind() == io::ErrorKind::InvalidInput => {}
+            _ => panic!("Accepted a short key"),
+        }
+        // The file is left alone, so the right key still works.
+        assert!(EncryptedDiskTokenStorage::new(&location, &[7u8; 32])
+            .unwrap()
+            .get(hash, &scopes)
+            .unwrap()
+            .is_some());
+        fs::remove_file(&location).unwrap();
+    }
+
+    #[test]
+    fn hash_scopes_ignores_order_and_duplicates() {
+        let (hash, scopes) = hash_scopes(&["b", "a", "a"]);
+        assert_eq!(scopes, vec!["a", "b"]);
+        assert_eq!(hash, hash_scopes(&["a", "b"]).0);
+        // The hash must not change between releases, or persisted tokens get lost.
+        assert_eq!(hash, 16591147884985137298);
+        assert_eq!(hash_scopes(&["https://www.googleapis.com/auth/youtube.upload",
+                                 "https://www.googleapis.com/auth/drive"])
+                       .0,
+                   3141647585515973125);
+
+        assert!(hash != hash_scopes(&["a"]).0);
+        assert!(hash != hash_scopes(&["a", "b", "c"]).0);
+        assert!(hash != hash_scopes(&["ab"]).0);
+    }
+
+    #[test]
+    fn tokens_of_several_accounts() {
+        let scopes = ["https://www.googleapis.com/auth/drive"];
+        let (alice, sorted_scopes) = hash_scopes_for_account("alice@example.com", &scopes);
+        let (bob, _) = hash_scopes_for_account("bob@example.com", &scopes);
+        assert!(alice != bob);
+        assert_eq!(hash_scopes_for_account("", &scopes).0, hash_scopes(&scopes).0);
+
+        let mut storage = MemoryStorage::default();
+        storage.set(alice, &sorted_scopes, Some(test_token("alice"))).unwrap();
+        storage.set(bob, &sorted_scopes, Some(test_token("bob"))).unwrap();
+        assert_eq!(storage.get(alice, &sorted_scopes).unwrap(), Some(test_token("alice")));
+        assert_eq!(storage.get(bob, &sorted_scopes).unwrap(), Some(test_token("bob")));
+    }
+
+    #[test]
+    fn migrate_token_to_account() {
+        let scopes = ["https://www.googleapis.com/auth/drive"];
+        let (old_key, sorted_scopes) = hash_scopes(&scopes);
+        let (new_key, _) = hash_scopes_for_account("alice@example.com", &scopes);
+        let mut storage = MemoryStorage::default();
+        storage.set(old_key, &sorted_scopes, Some(test_token("legacy"))).unwrap();
+
+        assert!(migrate_to_account(&mut storage, "alice@example.com", &scopes).unwrap());
+        assert_eq!(storage.get(new_key, &sorted_scopes).unwrap(), Some(test_token("legacy")));
+        assert_eq!(storage.get(old_key, &sorted_scopes).unwrap(), None);
+        assert!(!migrate_to_account(&mut storage, "alice@example.com", &scopes).unwrap());
+    }
+
+    #[test]
+    fn memory_storage_roundtrip() {
+        let mut storage = MemoryStorage::default();
+        let (upload_hash, upload_scopes) =
+            hash_scopes(&["https://www.googleapis.com/auth/youtube.upload"]);
+        let (pubsub_hash, pubsub_scopes) = hash_scopes(&["https://www.googleapis.com/auth/pubsub"]);
+
+        storage.set(upload_hash, &upload_scopes, Some(test_token("upload"))).unwrap();
+        storage.set(pubsub_hash, &pubsub_scopes, Some(test_token("pubsub"))).unwrap();
+        assert_eq!(storage.get(upload_hash, &upload_scopes).unwrap(),
+                   Some(test_token("upload")));
+        assert_eq!(storage.get(pubsub_hash, &pubsub_scopes).unwrap(),
+                   Some(test_token("pubsub")));
+
+        storage.set(upload_hash, &upload_scopes, None).unwrap();
+        assert_eq!(storage.get(upload_hash, &upload_scopes).unwrap(), None);
+
+        storage.clear();
+        assert_eq!(storage.get(pubsub_hash, &pubsub_scopes).unwrap(), None);
+    }
+
+    #[test]
+    fn memory_storage_export_import() {
+        let mut storage = MemoryStorage::default();
+        let (upload_hash, upload_scopes) =
+            hash_scopes(&["https://www.googleapis.com/auth/youtube.upload"]);
+        storage.set(upload_hash, &upload_scopes, Some(test_token("upload"))).unwrap();
+        // Stored under a hash of no scopes, like those of another application or account.
+        storage.set(42, &vec![], Some(test_token("unknown"))).unwrap();
+
+        let exported = storage.export();
+        let mut imported = MemoryStorage::default();
+        imported.import(&exported).unwrap();
+        assert_eq!(imported.tokens, storage.tokens);
+        assert_eq!(imported.get(42, &vec![]).unwrap(), Some(test_token("unknown")));
+
+        // Imports add to what is stored.
+        let mut other = MemoryStorage::default();
+        let (pubsub_hash, pubsub_scopes) = hash_scopes(&["https://www.googleapis.com/auth/pubsub"]);
+        other.set(pubsub_hash, &pubsub_scopes, Some(test_token("pubsub"))).unwrap();
+        other.import(&exported).unwrap();
+        assert_eq!(other.tokens.len(), 3);
+
+        match other.import("{\"tokens\":") {
+            Err(ref err) => assert_eq!(err.kind(), io::ErrorKind::InvalidData),
+            Ok(_) => panic!("Expected an error"),
+        }
+        assert_eq!(other.tokens.len(), 3);
+    }
 }
@@ -0,0 +1,269 @@
+//! [OAuth 2.0 Token Exchange](https://tools.ietf.org/html/rfc8693), with which a client trades a
+//! token it holds, e.g. one issued by another identity provider, for one to call a different
+//! service with.
+
+use std::borrow::BorrowMut;
+
+use hyper;
+use hyper::header::Headers;
+use itertools::Itertools;
+use serde_json as json;
+
+use client_credentials::request_token;
+use clock::SystemClock;
+use error::Error;
+use refresh::{body_snippet, token_from_body};
+use types::{ensure_https, ApplicationSecret, ClientAuthMethod, Token, DEFAULT_USER_AGENT};
+
+const TOKEN_EXCHANGE_GRANT_TYPE: &'static str = "urn:ietf:params:oauth:grant-type:token-exchange";
+
+/// Identifies an OAuth 2.0 access token, as `subject_token_type` or requested token type.
+pub const ACCESS_TOKEN_TYPE: &'static str = "urn:ietf:params:oauth:token-type:access_token";
+/// Identifies an OAuth 2.0 refresh token.
+pub const REFRESH_TOKEN_TYPE: &'static str = "urn:ietf:params:oauth:token-type:refresh_token";
+/// Identifies an OpenID Connect ID token.
+pub const ID_TOKEN_TYPE: &'static str = "urn:ietf:params:oauth:token-type:id_token";
+/// Identifies a JWT, e.g. one issued by a workload identity provider.
+pub const JWT_TOKEN_TYPE: &'static str = "urn:ietf:params:oauth:token-type:jwt";
+
+/// A token issued by `TokenExchangeFlow::obtain_token()`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExchangedToken {
+    /// The issued token. Its `access_token` holds the token of whatever type
+    /// `issued_token_type` says, e.g. a JWT.
+    pub token: Token,
+    /// The type of the issued token, like `ACCESS_TOKEN_TYPE`.
+    pub issued_token_type: String,
+}
+
+/// Exchanges a subject token for a new token, as described by
+/// [RFC 8693](https://tools.ietf.org/html/rfc8693). The `ApplicationSecret` names the token
+/// endpoint, and the client credentials to authenticate with.
+///
+/// Use `with_audience()` or `with_resource()` to name the service the new token is meant for.
+///
+/// # Examples
+/// ```no_run
+/// # extern crate hyper;
+/// # extern crate yup_oauth2 as oauth2;
+/// # fn main() {
+/// # let secret = oauth2::Provider::microsoft().application_secret("client-id", "secret");
+/// # let id_token = "eyJhbGciOiJSUzI1NiJ9.e30.c2lnbmF0dXJl";
+/// let no_scopes: [&str; 0] = [];
+/// let exchanged = oauth2::TokenExchangeFlow::new(hyper::Client::new())
+///     .with_audience("https://api.example.com")
+///     .obtain_token(&secret, id_token, oauth2::ID_TOKEN_TYPE, &no_scopes)
+///     .unwrap();
+/// assert_eq!(exchanged.issued_token_type, oauth2::ACCESS_TOKEN_TYPE);
+/// # }
+/// ```
+pub struct TokenExchangeFlow<C> {
+    client: C,
+    client_auth: ClientAuthMethod,
+    audience: Option<String>,
+    resource: Option<String>,
+    requested_token_type: Option<String>,
+    user_agent: String,
+    headers: Headers,
+    insecure_http: bool,
+}
+
+impl<C> TokenExchangeFlow<C>
+    where C: BorrowMut<hyper::Client>
+{
+    /// Returns a flow sending its requests through `client`.
+    pub fn new(client: C) -> TokenExchangeFlow<C> {
+        TokenExchangeFlow {
+            client: client,
+            client_auth: ClientAuthMethod::default(),
+            audience: None,
+            resource: None,
+            requested_token_type: None,
+            user_agent: DEFAULT_USER_AGENT.to_string(),
+            headers: Headers::new(),
+            insecure_http: false,
+        }
+    }
+
+    /// Ask for a token to be used with the service named `audience`, which is a logical name
+    /// the token endpoint knows, like a client ID.
+    pub fn with_audience(mut self, audience: &str) -> TokenExchangeFlow<C> {
+        self.audience = Some(audience.to_string());
+        self
+    }
+
+    /// Ask for a token to be used at `resource`, the URI of the target service.
+    pub fn with_resource(mut self, resource: &str) -> TokenExchangeFlow<C> {
+        self.resource = Some(resource.to_string());
+        self
+    }
+
+    /// Ask for a token of type `token_type`, like `JWT_TOKEN_TYPE`. Without it, the token
+    /// endpoint picks the type, usually an access token.
+    pub fn with_requested_token_type(mut self, token_type: &str) -> TokenExchangeFlow<C> {
+        self.requested_token_type = Some(token_type.to_string());
+        self
+    }
+
+    /// Choose how to present the client credentials to the token endpoint. By default,
+    /// they are sent in the request body.
+    pub fn with_client_auth(mut self, method: ClientAuthMethod) -> TokenExchangeFlow<C> {
+        self.client_auth = method;
+        self
+    }
+
+    /// Send `user_agent` as `User-Agent` header, instead of `DEFAULT_USER_AGENT`.
+    pub fn with_user_agent(mut self, user_agent: String) -> TokenExchangeFlow<C> {
+        self.user_agent = user_agent;
+        self
+    }
+
+    /// Add `headers` to every exchange request. The headers the flow sets itself take
+    /// precedence over them.
+    pub fn with_headers(mut self, headers: Headers) -> TokenExchangeFlow<C> {
+        self.headers = headers;
+        self
+    }
+
+    /// Allow sending tokens to a token endpoint which doesn't use HTTPS, e.g. a local test
+    /// server. By default, `obtain_token()` fails with `Error::InsecureUri` instead.
+    pub fn with_insecure_http(mut self, allow: bool) -> TokenExchangeFlow<C> {
+        self.insecure_http = allow;
+        self
+    }
+
+    /// Asks the token endpoint of `client_secret` for a token in exchange of `subject_token`,
+    /// whose type is `subject_token_type`, e.g. `ACCESS_TOKEN_TYPE`. No scope is sent if
+    /// `scopes` is empty.
+    ///
+    /// The issued token usually has no refresh token; exchange the subject token again once it
+    /// expires. A response which doesn't name the `issued_token_type`, as RFC 8693 requires it
+    /// to, fails with `Error::InvalidResponse`.
+    pub fn obtain_token<'a, I, T>(&mut self,
+                                  client_secret: &ApplicationSecret,
+                                  subject_token: &str,
+                                  subject_token_type: &str,
+                                  scopes: I)
+                                  -> Result<ExchangedToken, Error>
+        where T: AsRef<str> + 'a,
+              I: IntoIterator<Item = &'a T>
+    {
+        if !self.insecure_http {
+            try!(ensure_https(&client_secret.token_uri));
+        }
+        let scope = scopes.into_iter()
+            .map(|s| s.as_ref())
+            .intersperse(" ")
+            .collect::<String>();
+        let mut params = vec![("grant_type", TOKEN_EXCHANGE_GRANT_TYPE),
+                              ("subject_token", subject_token),
+                              ("subject_token_type", subject_token_type)];
+        if let Some(ref token_type) = self.requested_token_type {
+            params.push(("requested_token_type", token_type));
+        }
+        if let Some(ref audience) = self.audience {
+            params.push(("audience", audience));
+        }
+        if let Some(ref resource) = self.resource {
+            params.push(("resource", resource));
+        }
+        if !scope.is_empty() {
+            params.push(("scope", &scope));
+        }
+        let body = try!(request_token(self.client.borrow_mut(),
+                                      client_secret,
+                                      self.client_auth,
+                                      params,
+                                      &self.headers,
+                                      &self.user_agent));
+        let token = try!(token_from_body(&body, &SystemClock));
+        let issued_token_type = json::from_str::<json::Value>(&body)
+            .ok()
+            .and_then(|value| {
+                value.get("issued_token_type").and_then(|t| t.as_str()).map(|t| t.to_string())
+            });
+        match issued_token_type {
+            Some(issued_token_type) => {
+                Ok(ExchangedToken {
+                    token: token,
+                    issued_token_type: issued_token_type,
+                })
+            }
+            None => {
+                Err(Error::InvalidResponse(format!("missing field `issued_token_type`, got: {}",
+                                                   body_snippet(&body))))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use refresh::tests::RecordingConnector;
+    use types::tests::SECRET;
+    use types::ConsoleApplicationSecret;
+
+    #[test]
+    fn token_exchange_flow() {
+        let secret = json::from_str::<ConsoleApplicationSecret>(SECRET).unwrap().installed.unwrap();
+        let connector = RecordingConnector::new(&["HTTP/1.1 200 OK\r\n\
+                                                   \r\n\
+                                                   {\"access_token\":\"1/fFAGRNJru1FTz70BzhT3Zg\",\
+                                                   \"issued_token_type\":\
+                                                   \"urn:ietf:params:oauth:token-type:access_token\",\
+                                                   \"token_type\":\"Bearer\",\"expires_in\":3600}",
+                                                  "HTTP/1.1 400 Bad Request\r\n\
+                                                   \r\n\
+                                                   {\"error\":\"invalid_target\"}",
+                                                  "HTTP/1.1 200 OK\r\n\
+                                                   \r\n\
+                                                   {\"access_token\":\"1/fFAGRNJru1FTz70BzhT3Zg\",\
+                                                   \"token_type\":\"Bearer\",\"expires_in\":3600}"]);
+        let requests = connector.requests.clone();
+        let mut flow = TokenExchangeFlow::new(hyper::Client::with_connector(connector))
+            .with_audience("backend-api")
+            .with_resource("https://api.example.com/orders")
+            .with_requested_token_type(ACCESS_TOKEN_TYPE);
+
+        let exchanged = flow.obtain_token(&secret,
+                          "eyJhbGciOiJSUzI1NiJ9.e30.c2lnbmF0dXJl",
+                          ID_TOKEN_TYPE,
+                          &["orders.read"])
+            .unwrap();
+        assert_eq!(exchanged.issued_token_type, ACCESS_TOKEN_TYPE);
+        let token = exchanged.token;
+        assert_eq!(token.access_token, "1/fFAGRNJru1FTz70BzhT3Zg");
+        assert_eq!(token.refresh_token, "");
+        assert!(!token.expired());
+
+        let request = requests.lock().unwrap()[0].clone();
+        assert!(!request.contains("Authorization: "));
+        let body = request.rsplit("\r\n").next().unwrap();
+        assert!(body.starts_with("grant_type=urn%3Aietf%3Aparams%3Aoauth%3Agrant-type%3Atoken-exchange\
+                                  &subject_token=eyJhbGciOiJSUzI1NiJ9.e30.c2lnbmF0dXJl\
+                                  &subject_token_type=urn%3Aietf%3Aparams%3Aoauth%3Atoken-type%3Aid_token\
+                                  &requested_token_type=\
+                                  urn%3Aietf%3Aparams%3Aoauth%3Atoken-type%3Aaccess_token\
+                                  &audience=backend-api\
+                                  &resource=https%3A%2F%2Fapi.example.com%2Forders\
+                                  &scope=orders.read\
+                                  &client_id="));
+        assert!(body.contains("&client_secret="));
+
+        let no_scopes: [&str; 0] = [];
+        match flow.obtain_token(&secret, "expired", ACCESS_TOKEN_TYPE, &no_scopes) {
+            Err(Error::AuthError(ref err, _)) => assert_eq!(err, "invalid_target"),
+            r => panic!("Expected an AuthError, got {:?}", r),
+        }
+        match flow.obtain_token(&secret,
+                                "eyJhbGciOiJSUzI1NiJ9.e30.c2lnbmF0dXJl",
+                                ID_TOKEN_TYPE,
+                                &no_scopes) {
+            Err(Error::InvalidResponse(ref reason)) => {
+                assert!(reason.starts_with("missing field `issued_token_type`"))
+            }
+            r => panic!("Expected an InvalidResponse, got {:?}", r),
+        }
+    }
+}
@@ -1,8 +1,22 @@
-use chrono::{DateTime, UTC, TimeZone};
+use chrono::{DateTime, Duration, UTC, TimeZone};
 use std::error::Error;
 use std::fmt;
+use std::io;
 use std::str::FromStr;
+use base64;
 use hyper;
+use serde::{de, Deserialize, Deserializer};
+use serde_json as json;
+
+use clock::{Clock, SystemClock};
+use error;
+use service_account::ServiceAccountKey;
+
+/// The `User-Agent` sent along with all requests, unless a flow is told otherwise.
+pub const DEFAULT_USER_AGENT: &'static str = concat!("yup-oauth2/", env!("CARGO_PKG_VERSION"));
+
+/// Shown instead of credentials in `Debug` output, so that they don't end up in logs.
+pub const REDACTED: &'static str = "<redacted>";
 
 /// A marker trait for all Flows
 pub trait Flow {
@@ -119,10 +133,12 @@ impl AsRef<str> for TokenType {
 
 impl FromStr for TokenType {
     type Err = ();
+    // Token types are case insensitive (RFC 6749, section 5.1), so `bearer` is accepted as well.
     fn from_str(s: &str) -> Result<TokenType, ()> {
-        match s {
-            "Bearer" => Ok(TokenType::Bearer),
-            _ => Err(()),
+        if s.eq_ignore_ascii_case(TokenType::Bearer.as_ref()) {
+            Ok(TokenType::Bearer)
+        } else {
+            Err(())
         }
     }
 }
@@ -178,7 +194,13 @@ impl FromStr for Scheme {
 /// absolute terms.
 ///
 /// Utility methods make common queries easier, see `expired()`.
-#[derive(Clone, PartialEq, Debug, Deserialize, Serialize)]
+///
+/// The `Debug` output leaves out the access, refresh and ID tokens.
+///
+/// Outside of this crate, build tokens with `Token::new()` and the `with_*()` methods, as more
+/// fields may be added in the future.
+#[derive(Clone, PartialEq, Deserialize, Serialize)]
+#[non_exhaustive]
 pub struct Token {
     /// used when authenticating calls to oauth2 enabled services.
     pub access_token: String,
@@ -188,22 +210,321 @@ pub struct Token {
     pub token_type: String,
     /// access_token will expire after this amount of time.
     /// Prefer using expiry_date()
+    #[serde(default, deserialize_with = "deserialize_optional_expires_in")]
     pub expires_in: Option<i64>,
     /// timestamp is seconds since epoch indicating when the token will expire in absolute terms.
     /// use expiry_date() to convert to DateTime.
     pub expires_in_timestamp: Option<i64>,
+    /// timestamp in seconds since epoch at which the token was obtained, as set by the flows.
+    /// Tokens stored by earlier versions don't have it.
+    #[serde(default)]
+    pub issued_at_timestamp: Option<i64>,
+    /// The scopes granted by the server, if it said so. They may differ from the requested ones.
+    pub scopes: Option<Vec<String>>,
+    /// The OpenID Connect ID token (a JWT), which is returned if the `openid` scope was
+    /// requested. See `decode_id_token_claims()`.
+    pub id_token: Option<String>,
+}
+
+impl fmt::Debug for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        f.debug_struct("Token")
+            .field("access_token", &REDACTED)
+            .field("refresh_token", &REDACTED)
+            .field("token_type", &self.token_type)
+            .field("expires_in", &self.expires_in)
+            .field("expires_in_timestamp", &self.expires_in_timestamp)
+            .field("issued_at_timestamp", &self.issued_at_timestamp)
+            .field("scopes", &self.scopes)
+            .field("id_token", &self.id_token.as_ref().map(|_| REDACTED))
+            .finish()
+    }
+}
+
+// Some providers send numbers as strings, e.g. `"expires_in":"3600"`.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum NumberOrString {
+    Number(i64),
+    String(String),
+}
+
+/// Deserializes the `expires_in` field of a token response, which is accepted as a number or as
+/// a string containing one.
+pub fn deserialize_expires_in<'de, D>(deserializer: D) -> Result<i64, D::Error>
+    where D: Deserializer<'de>
+{
+    match try!(NumberOrString::deserialize(deserializer)) {
+        NumberOrString::Number(n) => Ok(n),
+        NumberOrString::String(s) => {
+            s.trim().parse().map_err(|_| {
+                de::Error::invalid_value(de::Unexpected::Str(&s), &"a number of seconds")
+            })
+        }
+    }
+}
+
+// The `aud` claim of a JWT is one audience, or a list of them (RFC 7519, section 4.1.3).
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OneOrMany {
+    One(String),
+    Many(Vec<String>),
+}
+
+/// Deserializes an `aud` claim, which is accepted as a single string or as a list of them,
+/// into a list.
+pub fn deserialize_audience<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+    where D: Deserializer<'de>
+{
+    match try!(OneOrMany::deserialize(deserializer)) {
+        OneOrMany::One(aud) => Ok(vec![aud]),
+        OneOrMany::Many(auds) => Ok(auds),
+    }
+}
+
+/// Like `deserialize_audience()`, for optional fields; use it along with `#[serde(default)]`.
+pub fn deserialize_optional_audience<'de, D>(deserializer: D)
+                                             -> Result<Option<Vec<String>>, D::Error>
+    where D: Deserializer<'de>
+{
+    #[derive(Deserialize)]
+    struct Wrapper(#[serde(deserialize_with = "deserialize_audience")] Vec<String>);
+
+    Ok(try!(Option::<Wrapper>::deserialize(deserializer)).map(|w| w.0))
+}
+
+/// Like `deserialize_expires_in()`, for optional fields; use it along with `#[serde(default)]`.
+pub fn deserialize_optional_expires_in<'de, D>(deserializer: D) -> Result<Option<i64>, D::Error>
+    where D: Deserializer<'de>
+{
+    #[derive(Deserialize)]
+    struct Wrapper(#[serde(deserialize_with = "deserialize_expires_in")] i64);
+
+    Ok(try!(Option::<Wrapper>::deserialize(deserializer)).map(|w| w.0))
+}
+
+/// Fails with `Error::InsecureUri` unless `uri` uses HTTPS, so that no credentials are sent in
+/// the clear.
+pub fn ensure_https(uri: &str) -> Result<(), error::Error> {
+    match uri.get(..8) {
+        Some(scheme) if scheme.eq_ignore_ascii_case("https://") => Ok(()),
+        _ => Err(error::Error::InsecureUri(uri.to_string())),
+    }
+}
+
+// The fields of a successful token response, see RFC 6749 section 5.1.
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    token_type: String,
+    #[serde(default, deserialize_with = "deserialize_optional_expires_in")]
+    expires_in: Option<i64>,
+    refresh_token: Option<String>,
+    scope: Option<String>,
+    id_token: Option<String>,
+}
+
+/// Splits the space-delimited `scope` field of a token response into the individual scopes.
+pub fn parse_scope(scope: &str) -> Vec<String> {
+    scope.split_whitespace().map(|s| s.to_string()).collect()
 }
 
 impl Token {
+    /// Returns a token without refresh token, scopes or ID token, and without an expiry, which
+    /// should be set with `with_expires_in()` or `with_expiry_timestamp()` before using it.
+    pub fn new(access_token: &str, token_type: &str) -> Token {
+        Token {
+            access_token: access_token.to_string(),
+            refresh_token: String::new(),
+            token_type: token_type.to_string(),
+            expires_in: None,
+            expires_in_timestamp: None,
+            issued_at_timestamp: None,
+            scopes: None,
+            id_token: None,
+        }
+    }
+
+    /// Builds a token from the JSON object a token endpoint answered with, e.g. when
+    /// implementing a flow this crate doesn't offer.
+    ///
+    /// `access_token` and `token_type` are required, while `expires_in`, `refresh_token`,
+    /// `scope` and `id_token` are optional. The token is issued now, and its expiry is made
+    /// absolute right away. An error response like `{"error":"invalid_grant"}` becomes an
+    /// `Error::AuthError`, and anything else not shaped like a token an `Error::JsonError`.
+    ///
+    /// # Examples
+    /// ```
+    /// # extern crate serde_json;
+    /// # extern crate yup_oauth2 as oauth2;
+    /// # fn main() {
+    /// let response = serde_json::from_str(r#"{"access_token":"1/fFAGRNJru1FTz70BzhT3Zg",
+    ///     "token_type":"Bearer","expires_in":3600}"#).unwrap();
+    /// let token = oauth2::Token::from_token_response(&response).unwrap();
+    /// assert_eq!(token.access_token, "1/fFAGRNJru1FTz70BzhT3Zg");
+    /// assert!(!token.expired());
+    /// # }
+    /// ```
+    pub fn from_token_response(json: &json::Value) -> Result<Token, error::Error> {
+        Token::from_token_response_with_clock(json, &SystemClock)
+    }
+
+    /// Like `from_token_response()`, computing the expiry from the time `clock` tells.
+    pub fn from_token_response_with_clock(json: &json::Value,
+                                          clock: &Clock)
+                                          -> Result<Token, error::Error> {
+        if json.get("error").is_some() {
+            if let Ok(err) = JsonError::deserialize(json) {
+                return Err(error::Error::from(err));
+            }
+        }
+        let t = try!(TokenResponse::deserialize(json));
+        let now = clock.now().timestamp();
+        Ok(Token {
+            access_token: t.access_token,
+            refresh_token: t.refresh_token.unwrap_or_default(),
+            token_type: t.token_type,
+            expires_in: t.expires_in,
+            expires_in_timestamp: t.expires_in.map(|e| now + e),
+            issued_at_timestamp: Some(now),
+            scopes: t.scope.as_ref().map(|s| parse_scope(s)),
+            id_token: t.id_token,
+        })
+    }
+
+    /// Sets the refresh token.
+    pub fn with_refresh_token(mut self, refresh_token: &str) -> Token {
+        self.refresh_token = refresh_token.to_string();
+        self
+    }
+
+    /// Lets the token expire `seconds` from now.
+    pub fn with_expires_in(mut self, seconds: i64) -> Token {
+        self.expires_in = Some(seconds);
+        self.expires_in_timestamp = Some(SystemClock.now().timestamp() + seconds);
+        self
+    }
+
+    /// Lets the token expire at `timestamp`, in seconds since the epoch.
+    pub fn with_expiry_timestamp(mut self, timestamp: i64) -> Token {
+        self.expires_in = None;
+        self.expires_in_timestamp = Some(timestamp);
+        self
+    }
+
+    /// Sets the scopes the token was granted for.
+    pub fn with_scopes<'a, I, T>(mut self, scopes: I) -> Token
+        where T: AsRef<str> + 'a,
+              I: IntoIterator<Item = &'a T>
+    {
+        self.scopes = Some(scopes.into_iter().map(|s| s.as_ref().to_string()).collect());
+        self
+    }
+
+    /// Sets the OpenID Connect ID token.
+    pub fn with_id_token(mut self, id_token: &str) -> Token {
+        self.id_token = Some(id_token.to_string());
+        self
+    }
+
     /// Returns true if we are expired.
     ///
+    /// A token without any expiry, e.g. because the server sent no `expires_in`, is never
+    /// considered expired; the only sign of it becoming invalid is an API refusing it, after
+    /// which a new one can be obtained with `Authenticator::force_token()`. A token with only a
+    /// relative expiry (see `set_expiry_absolute()`) is always considered expired, as there is
+    /// no telling when it started counting.
+    ///
     /// # Panics
     /// * if our access_token is unset
     pub fn expired(&self) -> bool {
+        self.expired_with_slack(Duration::zero())
+    }
+
+    /// Returns true if we are expired, or will expire within `slack` from now.
+    /// Use it to refresh tokens a little early, so they don't expire while a request
+    /// is in flight.
+    ///
+    /// # Panics
+    /// * if our access_token is unset
+    pub fn expired_with_slack(&self, slack: Duration) -> bool {
+        self.expired_with_clock(slack, &SystemClock)
+    }
+
+    /// Like `expired_with_slack()`, but asks `clock` for the current time.
+    ///
+    /// # Panics
+    /// * if our access_token is unset
+    pub fn expired_with_clock(&self, slack: Duration, clock: &Clock) -> bool {
         if self.access_token.len() == 0 {
             panic!("called expired() on unset token");
         }
-        self.expiry_date() <= UTC::now()
+        match (self.expiration_time(), self.expires_in) {
+            (Some(expiry), _) => expiry - slack <= clock.now(),
+            (None, Some(_)) => true,
+            (None, None) => false,
+        }
+    }
+
+    /// Returns true if the token is expired, or will expire within `slack`, and can be
+    /// refreshed, as it has a refresh token. See also `needs_reauth()`.
+    ///
+    /// # Panics
+    /// * if our access_token is unset
+    pub fn needs_refresh(&self, slack: Duration) -> bool {
+        self.expired_with_slack(slack) && !self.refresh_token.is_empty()
+    }
+
+    /// Returns true if the token is expired, or will expire within `slack`, but has no refresh
+    /// token. Whoever authorized it has to do so again, e.g. by running the installed flow.
+    ///
+    /// # Panics
+    /// * if our access_token is unset
+    pub fn needs_reauth(&self, slack: Duration) -> bool {
+        self.expired_with_slack(slack) && self.refresh_token.is_empty()
+    }
+
+    /// Returns true if the token was granted all of `scopes`, according to its `scopes` field.
+    /// A token whose granted scopes are unknown covers none.
+    pub fn covers_scopes(&self, scopes: &[&str]) -> bool {
+        match self.scopes {
+            Some(ref granted) => scopes.iter().all(|s| granted.iter().any(|g| g == s)),
+            None => false,
+        }
+    }
+
+    /// Returns the value of an `Authorization` header authenticating a request with this
+    /// token, e.g. `Bearer 1/fFAGRNJru1FTz70BzhT3Zg`.
+    ///
+    /// Providers don't agree on the casing of `bearer`, which is why it is always sent
+    /// as `Bearer`.
+    pub fn authorization_header_value(&self) -> String {
+        let token_type = if self.token_type.eq_ignore_ascii_case(TokenType::Bearer.as_ref()) {
+            TokenType::Bearer.as_ref()
+        } else {
+            &self.token_type
+        };
+        format!("{} {}", token_type, self.access_token)
+    }
+
+    /// Decodes the claims of the `id_token`, like `sub` or `email`, or returns `None` if there is
+    /// no ID token.
+    ///
+    /// **The signature of the token is not verified.** Only trust the claims if the token was
+    /// obtained from the provider directly, over TLS.
+    pub fn decode_id_token_claims(&self) -> Result<Option<json::Value>, error::Error> {
+        let id_token = match self.id_token {
+            None => return Ok(None),
+            Some(ref t) => t,
+        };
+        let payload = match id_token.split('.').nth(1) {
+            Some(p) => p,
+            None => return Err(error::Error::InvalidIdToken("not a JWT".to_string())),
+        };
+        let decoded = try!(base64::decode_config(payload, base64::URL_SAFE_NO_PAD)
+            .map_err(|e| error::Error::InvalidIdToken(format!("bad encoding: {}", e))));
+        Ok(Some(try!(json::from_slice(&decoded))))
     }
 
     /// Returns a DateTime object representing our expiry date.
@@ -213,15 +534,40 @@ impl Token {
                       0)
     }
 
-    /// Adjust our stored expiry format to be absolute, using the current time.
+    /// Returns the time at which the token expires, or `None` if it has no absolute expiry
+    /// (see `set_expiry_absolute()`).
+    pub fn expiration_time(&self) -> Option<DateTime<UTC>> {
+        self.expires_in_timestamp.map(|t| UTC.timestamp(t, 0))
+    }
+
+    /// Returns the time at which the token was obtained, or `None` if it isn't known.
+    pub fn issued_at(&self) -> Option<DateTime<UTC>> {
+        self.issued_at_timestamp.map(|t| UTC.timestamp(t, 0))
+    }
+
+    /// Returns how long the token remains valid, which is negative if it has already expired,
+    /// or `None` if it has no absolute expiry.
+    pub fn time_until_expiry(&self) -> Option<Duration> {
+        self.time_until_expiry_with_clock(&SystemClock)
+    }
+
+    /// Like `time_until_expiry()`, but asks `clock` for the current time.
+    pub fn time_until_expiry_with_clock(&self, clock: &Clock) -> Option<Duration> {
+        self.expiration_time().map(|t| t - clock.now())
+    }
+
+    /// Adjust our stored expiry format to be absolute, counting from the time the token was
+    /// issued, or from now if that isn't known. A token without any expiry is left as it is.
     pub fn set_expiry_absolute(&mut self) -> &mut Token {
         if self.expires_in_timestamp.is_some() {
-            assert!(self.expires_in.is_none());
             return self;
         }
-
-        self.expires_in_timestamp = Some(UTC::now().timestamp() + self.expires_in.unwrap());
-        self.expires_in = None;
+        if let Some(expires_in) = self.expires_in {
+            let issued_at = self.issued_at_timestamp
+                .unwrap_or_else(|| SystemClock.now().timestamp());
+            self.expires_in_timestamp = Some(issued_at + expires_in);
+            self.expires_in = None;
+        }
         self
     }
 }
@@ -240,13 +586,70 @@ pub enum FlowType {
     /// Same as InstalledInteractive, but uses a redirect: The OAuth provider redirects the user's
     /// browser to a web server that is running on localhost. This may not work as well with the
     /// Windows Firewall, but is more comfortable otherwise. The integer describes which port to
-    /// bind to (default: 8080)
+    /// bind to (default: 8080); with 0, a free port is chosen.
     InstalledRedirect(u32),
+    /// [service account flow](https://developers.google.com/identity/protocols/OAuth2ServiceAccount).
+    /// Tokens are obtained without user interaction by signing a JWT with the given key. Such
+    /// tokens come without a refresh token; once expired, a new one is requested.
+    ServiceAccount(ServiceAccountKey),
+}
+
+/// The state of a token, as reported by a [token introspection](https://tools.ietf.org/html/rfc7662)
+/// endpoint. See `Authenticator::introspect_token()`.
+///
+/// Apart from `active`, all fields are optional, and usually only present for active tokens.
+#[derive(Deserialize, Serialize, Clone, PartialEq, Debug)]
+pub struct Introspection {
+    /// Whether the token is currently valid
+    pub active: bool,
+    /// Space-separated list of the scopes the token grants
+    pub scope: Option<String>,
+    /// The client the token was issued to
+    pub client_id: Option<String>,
+    /// Name of the resource owner who authorized the token
+    pub username: Option<String>,
+    pub token_type: Option<String>,
+    /// Expiry as seconds since the epoch
+    pub exp: Option<i64>,
+    /// Time of issuance as seconds since the epoch
+    pub iat: Option<i64>,
+    /// Subject of the token, usually a machine-readable user identifier
+    pub sub: Option<String>,
+    /// Intended audiences of the token; given as a single string or a list of them
+    #[serde(default, deserialize_with = "deserialize_optional_audience")]
+    pub aud: Option<Vec<String>>,
+    /// Issuer of the token
+    pub iss: Option<String>,
+}
+
+/// How a client authenticates itself at the token endpoint.
+/// See [RFC 6749, section 2.3.1](https://tools.ietf.org/html/rfc6749#section-2.3.1).
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ClientAuthMethod {
+    /// `client_id` and `client_secret` are sent as part of the form-encoded request body
+    /// (default). This is what Google expects.
+    RequestBody,
+    /// `client_id` and `client_secret` are sent in an `Authorization: Basic` header, and the
+    /// secret is left out of the request body.
+    BasicHeader,
+    /// Only `client_id` is sent; the client authenticates with the certificate it presents
+    /// during the TLS handshake instead of its secret (`tls_client_auth` of
+    /// [RFC 8705](https://tools.ietf.org/html/rfc8705)). The flows must be given a client with
+    /// that certificate, like one of `client_from_env_with_identity()`.
+    TlsClientAuth,
+}
+
+impl Default for ClientAuthMethod {
+    fn default() -> ClientAuthMethod {
+        ClientAuthMethod::RequestBody
+    }
 }
 
 /// Represents either 'installed' or 'web' applications in a json secrets file.
 /// See `ConsoleApplicationSecret` for more information
-#[derive(Deserialize, Serialize, Clone, Default)]
+///
+/// The `Debug` output leaves out the client secret.
+#[derive(Deserialize, Serialize, Clone, Default, PartialEq)]
 pub struct ApplicationSecret {
     /// The client ID.
     pub client_id: String,
@@ -269,11 +672,127 @@ pub struct ApplicationSecret {
     pub client_x509_cert_url: Option<String>,
 }
 
+impl fmt::Debug for ApplicationSecret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        f.debug_struct("ApplicationSecret")
+            .field("client_id", &self.client_id)
+            .field("client_secret", &REDACTED)
+            .field("token_uri", &self.token_uri)
+            .field("auth_uri", &self.auth_uri)
+            .field("redirect_uris", &self.redirect_uris)
+            .field("project_id", &self.project_id)
+            .field("client_email", &self.client_email)
+            .field("auth_provider_x509_cert_url", &self.auth_provider_x509_cert_url)
+            .field("client_x509_cert_url", &self.client_x509_cert_url)
+            .finish()
+    }
+}
+
+/// Rejects secrets which would only fail once used for a request, as one of the client ID,
+/// client secret, token URI or authorization URI is empty.
+pub fn validate_application_secret(secret: ApplicationSecret) -> io::Result<ApplicationSecret> {
+    for &(name, value) in &[("client_id", &secret.client_id),
+                            ("client_secret", &secret.client_secret),
+                            ("token_uri", &secret.token_uri),
+                            ("auth_uri", &secret.auth_uri)] {
+        if value.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      format!("Bad application secret: empty {}", name)));
+        }
+    }
+    Ok(secret)
+}
+
+/// Assembles an `ApplicationSecret` field by field, e.g. from a configuration system, without
+/// breaking when fields are added to it.
+///
+/// # Examples
+/// ```
+/// # extern crate yup_oauth2 as oauth2;
+/// # fn main() {
+/// let secret = oauth2::ApplicationSecretBuilder::new()
+///     .with_client_id("client-id")
+///     .with_client_secret("secret")
+///     .with_auth_uri("https://accounts.google.com/o/oauth2/auth")
+///     .with_token_uri("https://accounts.google.com/o/oauth2/token")
+///     .build()
+///     .unwrap();
+/// # }
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct ApplicationSecretBuilder {
+    secret: ApplicationSecret,
+}
+
+impl ApplicationSecretBuilder {
+    pub fn new() -> ApplicationSecretBuilder {
+        ApplicationSecretBuilder::default()
+    }
+
+    pub fn with_client_id(mut self, client_id: &str) -> ApplicationSecretBuilder {
+        self.secret.client_id = client_id.to_string();
+        self
+    }
+
+    pub fn with_client_secret(mut self, client_secret: &str) -> ApplicationSecretBuilder {
+        self.secret.client_secret = client_secret.to_string();
+        self
+    }
+
+    pub fn with_token_uri(mut self, token_uri: &str) -> ApplicationSecretBuilder {
+        self.secret.token_uri = token_uri.to_string();
+        self
+    }
+
+    pub fn with_auth_uri(mut self, auth_uri: &str) -> ApplicationSecretBuilder {
+        self.secret.auth_uri = auth_uri.to_string();
+        self
+    }
+
+    pub fn with_redirect_uris(mut self, redirect_uris: Vec<String>) -> ApplicationSecretBuilder {
+        self.secret.redirect_uris = redirect_uris;
+        self
+    }
+
+    pub fn with_project_id(mut self, project_id: &str) -> ApplicationSecretBuilder {
+        self.secret.project_id = Some(project_id.to_string());
+        self
+    }
+
+    pub fn with_client_email(mut self, client_email: &str) -> ApplicationSecretBuilder {
+        self.secret.client_email = Some(client_email.to_string());
+        self
+    }
+
+    pub fn with_auth_provider_x509_cert_url(mut self, url: &str) -> ApplicationSecretBuilder {
+        self.secret.auth_provider_x509_cert_url = Some(url.to_string());
+        self
+    }
+
+    pub fn with_client_x509_cert_url(mut self, url: &str) -> ApplicationSecretBuilder {
+        self.secret.client_x509_cert_url = Some(url.to_string());
+        self
+    }
+
+    /// Returns the secret, or an error of kind `InvalidData` if the client ID, client secret,
+    /// token URI or authorization URI is missing.
+    pub fn build(self) -> io::Result<ApplicationSecret> {
+        validate_application_secret(self.secret)
+    }
+}
+
 /// A type to facilitate reading and writing the json secret file
 /// as returned by the [google developer console](https://code.google.com/apis/console)
-#[derive(Deserialize, Serialize, Default)]
+///
+/// Exactly one of the fields is usually populated. Serializing omits the other one, so a secret
+/// can be written back out in the shape it was read.
+#[derive(Deserialize, Serialize, Default, PartialEq, Debug)]
 pub struct ConsoleApplicationSecret {
+    /// The secret of a web application
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub web: Option<ApplicationSecret>,
+    /// The secret of an installed (native) application
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub installed: Option<ApplicationSecret>,
 }
 
@@ -290,6 +809,8 @@ pub mod tests {
          \"14070749909-vgip2f1okm7bkvajhi9jugan6126io9v.apps.googleusercontent.com\",\
          \"auth_provider_x509_cert_url\":\"https://www.googleapis.com/oauth2/v1/certs\"}}";
 
+    pub const TEST_APP_SECRET: &'static str = r#"{"installed":{"client_id":"384278056379-tr5pbot1mil66749n639jo54i4840u77.apps.googleusercontent.com","project_id":"sanguine-rhythm-105020","auth_uri":"https://accounts.google.com/o/oauth2/auth","token_uri":"https://accounts.google.com/o/oauth2/token","auth_provider_x509_cert_url":"https://www.googleapis.com/oauth2/v1/certs","client_secret":"QeQUnhzsiO4t--ZGmj9muUAu","redirect_uris":["urn:ietf:wg:oauth:2.0:oob","http://localhost"]}}"#;
+
     #[test]
     fn console_secret() {
         use serde_json as json;
@@ -299,6 +820,36 @@ pub mod tests {
         }
     }
 
+    #[test]
+    fn secret_debug_is_redacted() {
+        use serde_json as json;
+        let secret = json::from_str::<ConsoleApplicationSecret>(SECRET).unwrap();
+        let debug = format!("{:?}", secret);
+        assert!(!debug.contains("UqkDJd5RFwnHoiG5x5Rub8SI"));
+        assert!(debug.contains("client_secret: \"<redacted>\""));
+        assert!(debug.contains("14070749909-vgip2f1okm7bkvajhi9jugan6126io9v"));
+        assert!(debug.contains("https://accounts.google.com/o/oauth2/token"));
+
+        let token = Token::new("1/fFAGRNJru1FTz70BzhT3Zg", "Bearer")
+            .with_refresh_token("1/6BMfW9j53gdGImsixUH6kU5RsR4zwI9lUVX-tqf8JXQ")
+            .with_expires_in(3600)
+            .with_id_token("eyJhbGciOiJSUzI1NiJ9.e30.c2ln");
+        let debug = format!("{:?}", token);
+        assert!(!debug.contains("fFAGRNJru1FTz70BzhT3Zg"));
+        assert!(!debug.contains("6BMfW9j53gdGImsixUH6kU5RsR4zwI9lUVX"));
+        assert!(!debug.contains("eyJhbGciOiJSUzI1NiJ9"));
+        assert!(debug.contains("expires_in: Some(3600)"));
+    }
+
+    #[test]
+    fn console_secret_roundtrip() {
+        use serde_json as json;
+        let secret = json::from_str::<ConsoleApplicationSecret>(SECRET).unwrap();
+        let serialized = json::to_string(&secret).unwrap();
+        assert!(!serialized.contains("\"web\""));
+        assert_eq!(json::from_str::<ConsoleApplicationSecret>(&serialized).unwrap(), secret);
+    }
+
     #[test]
     fn schema() {
         let s = Scheme {
@@ -318,4 +869,246 @@ pub mod tests {
         assert_eq!(auth.0.token_type, TokenType::Bearer);
         assert_eq!(auth.0.access_token, "foo".to_string());
     }
+
+    #[test]
+    fn token_json_roundtrip() {
+        use serde_json as json;
+
+        let token = Token::new("1/fFAGRNJru1FTz70BzhT3Zg", "Bearer")
+            .with_refresh_token("1/6BMfW9j53gdGImsixUH6kU5RsR4zwI9lUVX-tqf8JXQ")
+            .with_expiry_timestamp(1500000000);
+        let serialized = json::to_string(&token).unwrap();
+        assert!(serialized.contains("\"expires_in_timestamp\":1500000000"));
+        assert_eq!(json::from_str::<Token>(&serialized).unwrap(), token);
+    }
+
+    #[test]
+    fn token_expired_with_slack() {
+        let token = Token::new("1/fFAGRNJru1FTz70BzhT3Zg", "Bearer")
+            .with_expiry_timestamp(UTC::now().timestamp() + 10);
+        assert!(!token.expired());
+        assert!(!token.expired_with_slack(Duration::seconds(5)));
+        assert!(token.expired_with_slack(Duration::seconds(30)));
+    }
+
+    #[test]
+    fn token_needs_refresh_or_reauth() {
+        let valid = Token::new("1/fFAGRNJru1FTz70BzhT3Zg", "Bearer")
+            .with_refresh_token("1/6BMfW9j53gdGImsixUH6kU5RsR4zwI9lUVX-tqf8JXQ")
+            .with_expires_in(3600);
+        assert!(!valid.needs_refresh(Duration::seconds(10)));
+        assert!(!valid.needs_reauth(Duration::seconds(10)));
+
+        let refreshable = valid.clone().with_expires_in(5);
+        assert!(refreshable.needs_refresh(Duration::seconds(10)));
+        assert!(!refreshable.needs_reauth(Duration::seconds(10)));
+
+        let mut unrefreshable = refreshable.clone();
+        unrefreshable.refresh_token = String::new();
+        assert!(!unrefreshable.needs_refresh(Duration::seconds(10)));
+        assert!(unrefreshable.needs_reauth(Duration::seconds(10)));
+        // Still valid, so there is nothing to do yet.
+        assert!(!unrefreshable.needs_reauth(Duration::zero()));
+    }
+
+    #[test]
+    fn token_expiry_counts_from_issue() {
+        let mut token = Token::new("1/fFAGRNJru1FTz70BzhT3Zg", "Bearer");
+        token.expires_in = Some(3600);
+        assert!(token.expired());
+        token.issued_at_timestamp = Some(1500000000);
+        assert_eq!(token.issued_at(), Some(UTC.timestamp(1500000000, 0)));
+        assert_eq!(token.set_expiry_absolute().expires_in_timestamp, Some(1500003600));
+        assert!(token.expired());
+    }
+
+    #[test]
+    fn token_expiration_time() {
+        let now = UTC::now().timestamp();
+        let mut token = Token::new("1/fFAGRNJru1FTz70BzhT3Zg", "Bearer")
+            .with_expiry_timestamp(now + 3600);
+        assert_eq!(token.expiration_time(), Some(UTC.timestamp(now + 3600, 0)));
+        let remaining = token.time_until_expiry().unwrap();
+        assert!(remaining > Duration::seconds(3500) && remaining <= Duration::seconds(3600));
+
+        token.expires_in_timestamp = Some(now - 60);
+        assert!(token.time_until_expiry().unwrap() <= Duration::seconds(-60));
+
+        token.expires_in_timestamp = None;
+        token.expires_in = Some(3600);
+        assert_eq!(token.expiration_time(), None);
+        assert_eq!(token.time_until_expiry(), None);
+    }
+
+    #[test]
+    fn expires_in_number_or_string() {
+        #[derive(Deserialize)]
+        struct Response {
+            #[serde(deserialize_with = "deserialize_expires_in")]
+            expires_in: i64,
+        }
+
+        for body in &[r#"{"expires_in":3600}"#, r#"{"expires_in":"3600"}"#] {
+            assert_eq!(json::from_str::<Response>(body).unwrap().expires_in, 3600);
+        }
+        assert!(json::from_str::<Response>(r#"{"expires_in":"soon"}"#).is_err());
+
+        let token: Token = json::from_str(r#"{"access_token":"1/fFAGRNJru1FTz70BzhT3Zg",
+            "refresh_token":"","token_type":"Bearer","expires_in":"3600",
+            "expires_in_timestamp":null}"#)
+            .unwrap();
+        assert_eq!(token.expires_in, Some(3600));
+    }
+
+    #[test]
+    fn token_from_token_response() {
+        use clock::MockClock;
+
+        let clock = MockClock::new(UTC.ymd(2017, 7, 14).and_hms(2, 40, 0));
+        let response: json::Value = json::from_str(r#"{"access_token":"1/fFAGRNJru1FTz70BzhT3Zg",
+            "token_type":"Bearer","expires_in":"3920",
+            "refresh_token":"1/6BMfW9j53gdGImsixUH6kU5RsR4zwI9lUVX-tqf8JXQ",
+            "scope":"https://www.googleapis.com/auth/youtube openid",
+            "id_token":"eyJhbGciOiJSUzI1NiJ9.e30.c2lnbmF0dXJl","ext_expires_in":3920}"#)
+            .unwrap();
+        let mut expected = Token::new("1/fFAGRNJru1FTz70BzhT3Zg", "Bearer")
+            .with_refresh_token("1/6BMfW9j53gdGImsixUH6kU5RsR4zwI9lUVX-tqf8JXQ")
+            .with_scopes(&["https://www.googleapis.com/auth/youtube", "openid"])
+            .with_id_token("eyJhbGciOiJSUzI1NiJ9.e30.c2lnbmF0dXJl")
+            .with_expiry_timestamp(clock.now().timestamp() + 3920);
+        expected.expires_in = Some(3920);
+        expected.issued_at_timestamp = Some(clock.now().timestamp());
+        assert_eq!(Token::from_token_response_with_clock(&response, &clock).unwrap(), expected);
+
+        let minimal = Token::from_token_response(&json::from_str(r#"{"access_token":"a",
+            "token_type":"bearer"}"#).unwrap()).unwrap();
+        assert_eq!(minimal.refresh_token, "");
+        assert_eq!(minimal.expiration_time(), None);
+
+        match Token::from_token_response(&json::from_str(r#"{"error":"invalid_grant",
+            "error_description":"Token has been expired or revoked."}"#).unwrap()) {
+            Err(error::Error::AuthError(ref err, Some(ref desc))) => {
+                assert_eq!(err, "invalid_grant");
+                assert_eq!(desc, "Token has been expired or revoked.");
+            }
+            r => panic!("Expected an AuthError, got {:?}", r),
+        }
+        match Token::from_token_response(&json::from_str(r#"{"accessToken":"a"}"#).unwrap()) {
+            Err(error::Error::JsonError(ref err)) => {
+                assert!(err.to_string().contains("access_token"))
+            }
+            r => panic!("Expected a JsonError, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn application_secret_builder() {
+        let expected = ApplicationSecret {
+            client_id: "384278056379-tr5pbot1mil66749n639jo54i4840u77.apps.googleusercontent.com"
+                .to_string(),
+            client_secret: "QeQUnhzsiO4t--ZGmj9muUAu".to_string(),
+            token_uri: "https://accounts.google.com/o/oauth2/token".to_string(),
+            auth_uri: "https://accounts.google.com/o/oauth2/auth".to_string(),
+            redirect_uris: vec!["urn:ietf:wg:oauth:2.0:oob".to_string()],
+            project_id: Some("sanguine-rhythm-105020".to_string()),
+            client_email: Some("oauth2-public-test@sanguine-rhythm-105020.iam.gserviceaccount.com"
+                .to_string()),
+            auth_provider_x509_cert_url: Some("https://www.googleapis.com/oauth2/v1/certs"
+                .to_string()),
+            client_x509_cert_url: Some("https://www.googleapis.com/robot/v1/metadata/x509/\
+                                        oauth2-public-test%40sanguine-rhythm-105020.iam.\
+                                        gserviceaccount.com"
+                .to_string()),
+        };
+        let builder = ApplicationSecretBuilder::new()
+            .with_client_id(&expected.client_id)
+            .with_client_secret(&expected.client_secret)
+            .with_token_uri(&expected.token_uri)
+            .with_auth_uri(&expected.auth_uri)
+            .with_redirect_uris(expected.redirect_uris.clone())
+            .with_project_id(expected.project_id.as_ref().unwrap())
+            .with_client_email(expected.client_email.as_ref().unwrap())
+            .with_auth_provider_x509_cert_url(expected.auth_provider_x509_cert_url
+                .as_ref()
+                .unwrap())
+            .with_client_x509_cert_url(expected.client_x509_cert_url.as_ref().unwrap());
+        assert_eq!(builder.clone().build().unwrap(), expected);
+
+        match builder.with_client_secret("").build() {
+            Err(ref err) => {
+                assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+                assert_eq!(err.to_string(), "Bad application secret: empty client_secret");
+            }
+            Ok(_) => panic!("Expected an error for the missing client secret"),
+        }
+    }
+
+    #[test]
+    fn covers_scopes() {
+        let token = Token::new("access", "Bearer").with_scopes(&["a", "b"]);
+        assert!(token.covers_scopes(&["a", "b"]));
+        assert!(token.covers_scopes(&["b"]));
+        assert!(!token.covers_scopes(&["a", "c"]));
+        assert!(!Token::new("access", "Bearer").covers_scopes(&["a"]));
+    }
+
+    #[test]
+    fn token_new() {
+        let token = Token::new("1/fFAGRNJru1FTz70BzhT3Zg", "Bearer");
+        assert_eq!(token.access_token, "1/fFAGRNJru1FTz70BzhT3Zg");
+        assert_eq!(token.token_type, "Bearer");
+        assert_eq!(token.refresh_token, "");
+        assert_eq!(token.expiration_time(), None);
+
+        let token = token.with_refresh_token("1/6BMfW9j53gdGImsixUH6kU5RsR4zwI9lUVX-tqf8JXQ")
+            .with_expires_in(3600)
+            .with_scopes(&["https://www.googleapis.com/auth/drive"])
+            .with_id_token("a.b.c");
+        assert_eq!(token.refresh_token, "1/6BMfW9j53gdGImsixUH6kU5RsR4zwI9lUVX-tqf8JXQ");
+        assert_eq!(token.expires_in, Some(3600));
+        assert!(!token.expired());
+        assert_eq!(token.scopes,
+                   Some(vec!["https://www.googleapis.com/auth/drive".to_string()]));
+        assert_eq!(token.id_token, Some("a.b.c".to_string()));
+
+        let token = token.with_expiry_timestamp(1500000000);
+        assert_eq!(token.expires_in, None);
+        assert!(token.expired());
+    }
+
+    #[test]
+    fn token_authorization_header_value() {
+        let mut token = Token::new("1/fFAGRNJru1FTz70BzhT3Zg", "Bearer").with_expires_in(3600);
+        assert_eq!(token.authorization_header_value(), "Bearer 1/fFAGRNJru1FTz70BzhT3Zg");
+        for token_type in &["bearer", "BEARER", "bEaReR"] {
+            token.token_type = token_type.to_string();
+            assert_eq!(token.authorization_header_value(), "Bearer 1/fFAGRNJru1FTz70BzhT3Zg");
+            // Only the header is normalized.
+            assert_eq!(token.token_type, *token_type);
+        }
+
+        let scheme = "BEARER 1/fFAGRNJru1FTz70BzhT3Zg".parse::<Scheme>().unwrap();
+        assert_eq!(scheme.token_type, TokenType::Bearer);
+        assert!("MAC 1/fFAGRNJru1FTz70BzhT3Zg".parse::<Scheme>().is_err());
+    }
+
+    #[test]
+    fn token_decode_id_token_claims() {
+        let mut token: Token = json::from_str(r#"{"access_token":"1/fFAGRNJru1FTz70BzhT3Zg",
+            "refresh_token":"","token_type":"Bearer","expires_in":3600,
+            "expires_in_timestamp":null}"#)
+            .unwrap();
+        assert_eq!(token.decode_id_token_claims().unwrap(), None);
+
+        token.id_token = Some("eyJhbGciOiJSUzI1NiJ9.\
+                               eyJzdWIiOiIxMTAxNjk0ODQ0NzQzODYyNzYzMzQiLCJlbWFpbCI6InVzZXJAZXhhbXBsZS5jb20ifQ.\
+                               c2lnbmF0dXJl"
+            .to_string());
+        let claims = token.decode_id_token_claims().unwrap().unwrap();
+        assert_eq!(claims["sub"], "110169484474386276334");
+        assert_eq!(claims["email"], "user@example.com");
+
+        token.id_token = Some("not-a-jwt".to_string());
+        assert!(token.decode_id_token_claims().is_err());
+    }
 }